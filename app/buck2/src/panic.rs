@@ -25,19 +25,24 @@ pub fn initialize(fb: FacebookInit) -> anyhow::Result<()> {
         the_panic_hook(fb, info);
         hook(info);
     }));
-    buck2_core::error::initialize(Box::new(move |category, err, loc, quiet| {
-        imp::write_soft_error(
-            fb,
-            category,
-            err,
-            buck2_data::Location {
-                file: loc.0.to_owned(),
-                line: loc.1,
-                column: loc.2,
-            },
-            quiet,
-        );
-    }))
+    buck2_core::error::initialize(Box::new(
+        move |category, err, loc, quiet, annotation, severity, backtrace| {
+            imp::write_soft_error(
+                fb,
+                category,
+                err,
+                buck2_data::Location {
+                    file: loc.0.to_owned(),
+                    line: loc.1,
+                    column: loc.2,
+                },
+                quiet,
+                annotation,
+                severity,
+                backtrace,
+            );
+        },
+    ))
     .context("Error initializing soft errors")?;
     Ok(())
 }
@@ -58,6 +63,8 @@ mod imp {
     use std::time::Duration;
 
     use backtrace::Backtrace;
+    use buck2_core::error::Severity;
+    use buck2_core::error::SoftErrorAnnotation;
     use buck2_data::Location;
     use buck2_events::metadata;
     use buck2_events::sink::scribe::new_thrift_scribe_sink_if_enabled;
@@ -150,14 +157,35 @@ mod imp {
         err: &anyhow::Error,
         location: Location,
         quiet: bool,
+        annotation: Option<&SoftErrorAnnotation>,
+        severity: Severity,
+        backtrace: Option<&std::backtrace::Backtrace>,
     ) {
-        let event = panic_payload(
+        let mut event = panic_payload(
             Some(location),
             format!("Soft Error: {}: {:#}", category, err),
             Vec::new(),
             quiet,
             Some(category),
         );
+        event
+            .metadata
+            .insert("soft_error_severity".to_owned(), severity.to_string());
+        if let Some(backtrace) = backtrace {
+            event
+                .metadata
+                .insert("soft_error_backtrace".to_owned(), backtrace.to_string());
+        }
+        if let Some(annotation) = annotation {
+            event
+                .metadata
+                .insert("soft_error_owner".to_owned(), annotation.owner.to_owned());
+            if let Some(task_link) = annotation.task_link {
+                event
+                    .metadata
+                    .insert("soft_error_task".to_owned(), task_link.to_owned());
+            }
+        }
 
         // If the soft error was fired in a context with an ambient dispatcher, then we only send
         // it there, but some contexts don't have one, and in that case, we notify all running
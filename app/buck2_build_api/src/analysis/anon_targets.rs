@@ -404,7 +404,10 @@ impl AnonTargetKey {
 
                     let list_res = rule_impl.invoke(&mut eval, ctx)?;
                     ctx.run_promises(dice, &mut eval).await?;
-                    let res_typed = ProviderCollection::try_from_value(list_res)?;
+                    let res_typed = ProviderCollection::try_from_value_for_target(
+                        list_res,
+                        &self.0.configured_label(),
+                    )?;
                     let res = env.heap().alloc(res_typed);
                     env.set("", res);
 
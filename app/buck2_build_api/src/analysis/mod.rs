@@ -13,6 +13,7 @@ use std::sync::Arc;
 
 use anyhow::Context as _;
 use buck2_common::result::SharedResult;
+use buck2_core::error::with_soft_error_target_async;
 use buck2_core::provider::label::ConfiguredProvidersLabel;
 use buck2_core::provider::label::ProvidersName;
 use buck2_core::target::label::ConfiguredTargetLabel;
@@ -331,7 +332,10 @@ async fn run_analysis_with_env_underlying(
         Some(profiler) => StarlarkProfilerOrInstrumentation::for_profiler(profiler),
     };
 
-    let analysis_registry = {
+    // Soft errors reported while running the rule, including its promises, are attributed to
+    // the target.
+    let target = analysis_env.label.unconfigured().to_string();
+    let analysis_registry = with_soft_error_target_async(&target, async {
         let mut eval = Evaluator::new(&env);
         eval.set_print_handler(&print);
 
@@ -341,7 +345,7 @@ async fn run_analysis_with_env_underlying(
             Some(
                 eval.heap()
                     .alloc_typed(Label::new(ConfiguredProvidersLabel::new(
-                        analysis_env.label,
+                        analysis_env.label.dupe(),
                         ProvidersName::Default,
                     ))),
             ),
@@ -360,13 +364,15 @@ async fn run_analysis_with_env_underlying(
         ctx.run_promises(dice, &mut eval).await?;
 
         // TODO: Convert the ValueError from `try_from_value` better than just printing its Debug
-        let res_typed = ProviderCollection::try_from_value(list_res)?;
+        let res_typed =
+            ProviderCollection::try_from_value_for_target(list_res, &analysis_env.label)?;
         let res = env.heap().alloc(res_typed);
         env.set_extra_value(res);
 
         // Pull the ctx object back out, and steal ctx.action's state back
-        ctx.take_state()
-    };
+        anyhow::Ok(ctx.take_state())
+    })
+    .await?;
 
     let (frozen_env, deferreds) = analysis_registry.finalize(&env)(env)?;
 
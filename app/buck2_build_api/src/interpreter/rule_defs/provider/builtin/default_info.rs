@@ -242,6 +242,10 @@ impl FrozenDefaultInfo {
         self.sub_targets
     }
 
+    pub fn other_outputs_raw(&self) -> FrozenValue {
+        self.other_outputs
+    }
+
     pub fn for_each_default_output_artifact_only(
         &self,
         processor: &mut dyn FnMut(Artifact) -> anyhow::Result<()>,
@@ -472,9 +476,16 @@ mod tests {
     use buck2_core::bzl::ImportPath;
     use buck2_interpreter_for_build::interpreter::testing::Tester;
     use indoc::indoc;
+    use starlark::values::dict::AllocDict;
+    use starlark::values::list::AllocList;
+    use starlark::values::FrozenHeap;
+    use starlark::values::FrozenValue;
 
     use crate::interpreter::build_defs::register_provider;
     use crate::interpreter::rule_defs::artifact::testing::artifactory;
+    use crate::interpreter::rule_defs::provider::collection::FrozenProviderCollection;
+    use crate::interpreter::rule_defs::provider::DefaultInfoCallable;
+    use crate::interpreter::rule_defs::provider::FrozenDefaultInfo;
     use crate::interpreter::rule_defs::register_rule_defs;
 
     #[test]
@@ -558,4 +569,35 @@ mod tests {
             "#
         ))
     }
+
+    #[test]
+    fn validate_subtargets() {
+        let heap = FrozenHeap::new();
+        // Build the collections by hand, since `DefaultInfo()` rejects malformed sub-targets.
+        let collection = |sub_targets: FrozenValue| FrozenProviderCollection {
+            providers: [(
+                DefaultInfoCallable::provider_id_t().interned(),
+                heap.alloc(FrozenDefaultInfo {
+                    sub_targets,
+                    default_outputs: heap.alloc(AllocList::EMPTY),
+                    other_outputs: heap.alloc(AllocList::EMPTY),
+                }),
+            )]
+            .into_iter()
+            .collect(),
+            default_info_synthesized: false,
+        };
+        let leaf = heap.alloc(collection(heap.alloc(AllocDict::EMPTY)));
+
+        let valid = collection(heap.alloc(AllocDict([("foo", leaf)])));
+        assert!(valid.validate_subtargets().is_ok());
+
+        let malformed = heap.alloc(collection(heap.alloc(AllocDict([("bar", 1)]))));
+        let nested = collection(heap.alloc(AllocDict([("foo", leaf), ("baz", malformed)])));
+        let err = nested.validate_subtargets().unwrap_err();
+        assert_eq!(
+            "sub-target `[baz][bar]` should be a provider collection, got `1`",
+            err.to_string()
+        );
+    }
 }
@@ -15,6 +15,7 @@ use std::sync::Arc;
 
 use allocative::Allocative;
 use buck2_core::cells::cell_path::CellPath;
+use buck2_core::provider::id::InternedProviderId;
 use buck2_core::provider::id::ProviderId;
 use buck2_interpreter_for_build::provider::callable::ProviderCallableLike;
 use dupe::Dupe;
@@ -111,9 +112,9 @@ impl UserProviderCallableImpl {
 /// Field values default to `None`
 #[derive(Debug, ProvidesStaticType, Trace, NoSerialize, Allocative)]
 pub struct UserProviderCallable {
-    /// The name of this provider, filled in by `export_as()`. This must be set before this
-    /// object can be called and Providers created.
-    id: RefCell<Option<Arc<ProviderId>>>,
+    /// The name of this provider and its interned version, filled in by `export_as()`. This
+    /// must be set before this object can be called and Providers created.
+    id: RefCell<Option<(Arc<ProviderId>, InternedProviderId)>>,
     /// The path where this `ProviderCallable` is created and assigned
     path: CellPath,
     /// The docstring for this provider
@@ -182,7 +183,14 @@ impl UserProviderCallable {
 impl ProviderCallableLike for UserProviderCallable {
     fn id(&self) -> Option<&Arc<ProviderId>> {
         // Safe because once we set id, we never change it
-        unsafe { self.id.try_borrow_unguarded().unwrap().as_ref() }
+        unsafe { self.id.try_borrow_unguarded().unwrap().as_ref() }.map(|(id, _)| id)
+    }
+
+    fn interned_id(&self) -> Option<InternedProviderId> {
+        self.id
+            .borrow()
+            .as_ref()
+            .map(|(_, interned_id)| *interned_id)
     }
 }
 
@@ -196,7 +204,7 @@ impl Freeze for UserProviderCallable {
     type Frozen = FrozenUserProviderCallable;
     fn freeze(self, _freezer: &Freezer) -> anyhow::Result<Self::Frozen> {
         let callable = self.callable.into_inner();
-        let id = match self.id.into_inner() {
+        let (id, interned_id) = match self.id.into_inner() {
             Some(x) => x,
             None => {
                 // Unfortunately we have no name or location for the provider at this point,
@@ -207,6 +215,7 @@ impl Freeze for UserProviderCallable {
 
         Ok(FrozenUserProviderCallable::new(
             id,
+            interned_id,
             self.docs,
             self.field_docs,
             self.fields,
@@ -226,7 +235,7 @@ impl<'v> StarlarkValue<'v> for UserProviderCallable {
                 path: Some(self.path.clone()),
                 name: variable_name.to_owned(),
             });
-            *id = Some(new_id.dupe());
+            *id = Some((new_id.dupe(), InternedProviderId::new(&new_id)));
             *self.callable.borrow_mut() = UserProviderCallableImpl::Bound(
                 create_callable_function_signature(&new_id.name, &self.fields),
                 eval.frozen_heap()
@@ -272,6 +281,8 @@ pub struct FrozenUserProviderCallable {
     /// The name of this provider, filled in by `export_as()`. This must be set before this
     /// object can be called and Providers created.
     id: Arc<ProviderId>,
+    /// `id`, interned.
+    interned_id: InternedProviderId,
     /// The docstring for this provider
     docs: Option<DocString>,
     /// The docstrings for each field. The length of must be identical to `fields`
@@ -299,6 +310,7 @@ impl Display for FrozenUserProviderCallable {
 impl FrozenUserProviderCallable {
     fn new(
         id: Arc<ProviderId>,
+        interned_id: InternedProviderId,
         docs: Option<DocString>,
         field_docs: Vec<Option<DocString>>,
         fields: SmallSet<String>,
@@ -313,6 +325,7 @@ impl FrozenUserProviderCallable {
         );
         Self {
             id,
+            interned_id,
             docs,
             field_docs,
             fields,
@@ -325,6 +338,10 @@ impl ProviderCallableLike for FrozenUserProviderCallable {
     fn id(&self) -> Option<&Arc<ProviderId>> {
         Some(&self.id)
     }
+
+    fn interned_id(&self) -> Option<InternedProviderId> {
+        Some(self.interned_id)
+    }
 }
 
 impl<'v> StarlarkValue<'v> for FrozenUserProviderCallable {
@@ -366,7 +383,7 @@ fn provider_callable_methods(builder: &mut MethodsBuilder) {
         if let Some(x) = this.downcast_ref::<UserProviderCallable>() {
             match &*x.id.borrow() {
                 None => Err(ProviderCallableError::ProviderNotAssigned(x.fields.clone()).into()),
-                Some(id) => Ok(heap.alloc(id.name.as_str())),
+                Some((id, _)) => Ok(heap.alloc(id.name.as_str())),
             }
         } else if let Some(x) = this.downcast_ref::<FrozenUserProviderCallable>() {
             Ok(heap.alloc(x.id.name.as_str()))
@@ -7,33 +7,63 @@
  * of this source tree.
  */
 
+use std::cell::Cell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::collections::HashSet;
 use std::fmt;
+use std::fmt::Debug;
 use std::fmt::Display;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::mem;
 use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
 
 use allocative::Allocative;
+use allocative::Visitor;
+use anyhow::Context;
+use buck2_common::result::SharedResult;
+use buck2_common::result::ToSharedResultExt;
+use buck2_core::env_helper::EnvHelper;
+use buck2_core::provider::id::InternedProviderId;
 use buck2_core::provider::id::ProviderId;
 use buck2_core::provider::id::ProviderIdWithType;
 use buck2_core::provider::label::ConfiguredProvidersLabel;
 use buck2_core::provider::label::NonDefaultProvidersName;
 use buck2_core::provider::label::ProviderName;
 use buck2_core::provider::label::ProvidersName;
+use buck2_core::soft_error;
+use buck2_core::target::label::ConfiguredTargetLabel;
 use buck2_interpreter::starlark_promise::StarlarkPromise;
 use buck2_interpreter_for_build::provider::callable::ValueAsProviderCallableLike;
 use display_container::display_container;
 use dupe::Dupe;
 use either::Either;
+use once_cell::sync::Lazy;
+use once_cell::sync::OnceCell;
+use serde::ser::SerializeStruct;
+use serde::Deserialize;
 use serde::Serialize;
 use serde::Serializer;
 use starlark::any::ProvidesStaticType;
 use starlark::coerce::Coerce;
 use starlark::collections::SmallMap;
+use starlark::collections::SmallSet;
 use starlark::environment::Methods;
 use starlark::environment::MethodsBuilder;
 use starlark::environment::MethodsStatic;
+use starlark::eval::Evaluator;
+use starlark::values::dict::FrozenDictRef;
 use starlark::values::list::ListRef;
+use starlark::values::none::NoneType;
 use starlark::values::Freeze;
 use starlark::values::Freezer;
+use starlark::values::FrozenHeap;
 use starlark::values::FrozenRef;
 use starlark::values::FrozenValue;
 use starlark::values::Heap;
@@ -45,23 +75,78 @@ use starlark::values::Tracer;
 use starlark::values::Value;
 use starlark::values::ValueLike;
 
+use crate::actions::artifact::artifact_type::Artifact;
+use crate::interpreter::rule_defs::artifact::StarlarkArtifact;
+use crate::interpreter::rule_defs::provider::deprecation::provider_deprecation;
+use crate::interpreter::rule_defs::provider::deprecation::DeprecationMessage;
+use crate::interpreter::rule_defs::provider::factory::synthesize_provider;
+use crate::interpreter::rule_defs::provider::protocol::ProviderProtocol;
+use crate::interpreter::rule_defs::provider::registration::builtin_provider_callable;
+use crate::interpreter::rule_defs::provider::schema::ProviderSchema;
 use crate::interpreter::rule_defs::provider::DefaultInfo;
 use crate::interpreter::rule_defs::provider::DefaultInfoCallable;
 use crate::interpreter::rule_defs::provider::FrozenDefaultInfo;
+use crate::interpreter::rule_defs::provider::ProviderLike;
 use crate::interpreter::rule_defs::provider::ValueAsProviderLike;
 
+/// How many provider names `FrozenProviderCollection::one_line` lists before eliding the rest.
+const MAX_PROVIDERS_IN_ONE_LINE: usize = 8;
+
+/// How many keys to list in `AtNotFound` before eliding the rest.
+const MAX_PROVIDER_KEYS_IN_ERROR: usize = 25;
+
 fn format_provider_keys_for_error(keys: &[String]) -> String {
-    format!(
+    // Set to list every available key, e.g. when debugging a collection with many providers.
+    static SHOW_ALL_KEYS: EnvHelper<bool> = EnvHelper::new("BUCK2_SHOW_ALL_PROVIDER_KEYS");
+    let limit = match SHOW_ALL_KEYS.get_copied() {
+        Ok(Some(true)) => None,
+        _ => Some(MAX_PROVIDER_KEYS_IN_ERROR),
+    };
+    format_provider_keys_truncated(keys, limit)
+}
+
+/// Whether indexing a collection with a string, e.g. `collection["FooInfo"]`, fails with an
+/// error pointing at the provider callable instead of the generic type error. For codebases that
+/// want to catch accidental lookups by name.
+fn strict_string_index() -> bool {
+    static STRICT: EnvHelper<bool> = EnvHelper::new("BUCK2_STRICT_PROVIDER_STRING_INDEX");
+    matches!(STRICT.get_copied(), Ok(Some(true)))
+}
+
+fn index_not_a_provider(
+    op: GetOp,
+    index: Value,
+    strict_string_index: bool,
+) -> ProviderCollectionError {
+    match index.unpack_str() {
+        Some(name) if strict_string_index => {
+            ProviderCollectionError::StringIndexDisabled(name.to_owned())
+        }
+        _ => ProviderCollectionError::AtTypeNotProvider(op, index.get_type()),
+    }
+}
+
+fn format_provider_keys_truncated(keys: &[String], limit: Option<usize>) -> String {
+    let mut keys: Vec<&str> = keys.iter().map(|k| k.as_str()).collect();
+    keys.sort_unstable();
+
+    let shown = limit.map_or(keys.len(), |limit| keys.len().min(limit));
+    let mut res = format!(
         "[{}]",
-        keys.iter()
+        keys[..shown]
+            .iter()
             .map(|k| format!("`{}`", k))
             .collect::<Vec<_>>()
             .join(", ")
-    )
+    );
+    if shown < keys.len() {
+        res.push_str(&format!(" ...and {} more", keys.len() - shown));
+    }
+    res
 }
 
 #[derive(Debug, thiserror::Error)]
-enum ProviderCollectionError {
+pub enum ProviderCollectionError {
     #[error("expected a list of Provider objects, got {repr}")]
     CollectionNotAList { repr: String },
     #[error("expected a Provider object, got {repr}")]
@@ -74,6 +159,8 @@ enum ProviderCollectionError {
     },
     #[error("collection {repr} did not receive a DefaultInfo provider")]
     CollectionMissingDefaultInfo { repr: String },
+    #[error("collection {repr} did not receive the required provider `{provider_name}`")]
+    CollectionMissingRequiredProvider { provider_name: String, repr: String },
     #[error(
         "requested sub target named `{0}` of target `{1}` is not available. Available subtargets are: `{2:?}`"
     )]
@@ -92,11 +179,177 @@ enum ProviderCollectionError {
         got `{1}`"
     )]
     AtTypeNotProvider(GetOp, &'static str),
+    #[error("string indexing is disabled; use the provider callable {0}")]
+    StringIndexDisabled(String),
     #[error(
         "provider collection does not have a key `{0}`, available keys are: {}",
         format_provider_keys_for_error(_1)
     )]
     AtNotFound(String, Vec<String>),
+    #[error("expected provider `{0}` is missing from the provider collection")]
+    ExpectedProviderMissing(String),
+    #[error("provider collection contains providers that are not allowed: {}", _0.join(", "))]
+    UnexpectedProviders(Vec<String>),
+    #[error("cannot remove `DefaultInfo` from a provider collection")]
+    CannotRemoveDefaultInfo,
+    #[error("providers {} are mutually exclusive, but more than one is present", _0.join(", "))]
+    MutuallyExclusiveProviders(Vec<String>),
+    #[error("cannot remap the id of `DefaultInfo`, or remap another provider to it")]
+    CannotRemapDefaultInfo,
+    #[error("remapping provider ids would specify provider `{0}` twice")]
+    RemapCollision(String),
+    #[error(
+        "`DefaultInfo` cannot be added to a partial provider collection, pass it to `finalize`"
+    )]
+    DefaultInfoInPartialCollection,
+    #[error("{0}")]
+    DeprecatedProvider(String),
+    #[error("deprecated providers are present: {}", _0.join("; "))]
+    DeprecatedProvidersPresent(Vec<String>),
+    #[error("cannot recover the callable of provider `{0}`, it is not a builtin provider")]
+    ProviderCallableUnavailable(String),
+    #[error("sub-targets of `DefaultInfo` at `{path}` should be a dict, got `{repr}`")]
+    SubTargetsNotADict { path: String, repr: String },
+    #[error("malformed provider collection snapshot")]
+    MalformedSnapshot,
+    #[error("provider collection slice bounds must be integers, got `{0}`")]
+    SliceBoundNotAnInt(String),
+    #[error("provider collection slices cannot have a step")]
+    SliceWithStep,
+    #[error("provider collection does not match its schema: {}", _0.join("; "))]
+    SchemaViolations(Vec<String>),
+    #[error("sub-target `{path}` should be a provider collection, got `{repr}`")]
+    MalformedSubTarget { path: String, repr: String },
+    #[error(
+        "merging conflicting `{provider_name}` providers should produce a `{provider_name}`, got `{repr}`"
+    )]
+    MergeResolvedToWrongProvider { provider_name: String, repr: String },
+    #[error(
+        "provider collection delta applies to a base with fingerprint `{expected}`, got `{actual}`"
+    )]
+    DeltaBaseMismatch { expected: String, actual: String },
+    #[error("artifact `{artifact}` is a default output of both {first} and {second}")]
+    DuplicateDefaultOutput {
+        artifact: String,
+        first: String,
+        second: String,
+    },
+}
+
+/// Time spent building a single provider collection, see
+/// `init_collection_construction_observer`.
+#[derive(Debug, Clone, Copy)]
+pub struct CollectionConstructionTiming {
+    pub provider_count: usize,
+    pub duration: Duration,
+}
+
+type CollectionConstructionObserver =
+    Box<dyn Fn(&CollectionConstructionTiming) + Send + Sync + 'static>;
+
+static CONSTRUCTION_OBSERVER: OnceCell<CollectionConstructionObserver> = OnceCell::new();
+
+/// Report how long each provider collection takes to build to `observer`, if
+/// `$BUCK2_PROVIDER_COLLECTION_TIMING` is set. Construction is not timed at all otherwise.
+pub fn init_collection_construction_observer(
+    observer: CollectionConstructionObserver,
+) -> anyhow::Result<()> {
+    static TIMING: EnvHelper<bool> = EnvHelper::new("BUCK2_PROVIDER_COLLECTION_TIMING");
+    if TIMING.get_copied()?.unwrap_or(false) {
+        set_collection_construction_observer(observer);
+    }
+    Ok(())
+}
+
+fn set_collection_construction_observer(observer: CollectionConstructionObserver) {
+    if CONSTRUCTION_OBSERVER.set(observer).is_err() {
+        panic!("Cannot initialize provider collection construction observer more than once");
+    }
+}
+
+type ConstructionErrorSink =
+    Box<dyn Fn(Option<&ConfiguredTargetLabel>, &ProviderCollectionError) + Send + Sync + 'static>;
+
+static CONSTRUCTION_ERROR_SINK: OnceCell<ConstructionErrorSink> = OnceCell::new();
+
+/// Pass every `ProviderCollectionError` from building a provider collection to `sink`, along
+/// with the target whose rule returned the providers when it's known. The errors are still
+/// returned to the caller as usual.
+pub fn init_construction_error_sink(sink: ConstructionErrorSink) {
+    if CONSTRUCTION_ERROR_SINK.set(sink).is_err() {
+        panic!("Cannot initialize provider collection construction error sink more than once");
+    }
+}
+
+fn report_construction_error(target: Option<&ConfiguredTargetLabel>, err: &anyhow::Error) {
+    if let Some(sink) = CONSTRUCTION_ERROR_SINK.get() {
+        if let Some(err) = err.downcast_ref::<ProviderCollectionError>() {
+            sink(target, err);
+        }
+    }
+}
+
+/// A provider collection construction error recorded by `ConstructionErrorCollector`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CollectedConstructionError {
+    /// The target whose rule returned the malformed providers, if known.
+    pub target: Option<ConfiguredTargetLabel>,
+    pub message: String,
+}
+
+/// Records provider collection construction errors over a build, to report every rule which
+/// returned malformed providers in one place at the end. Install it with
+/// `init_construction_error_sink(collector.sink())` and collect the errors with `drain`.
+#[derive(Clone, Dupe, Default)]
+pub struct ConstructionErrorCollector {
+    errors: Arc<Mutex<Vec<CollectedConstructionError>>>,
+}
+
+impl ConstructionErrorCollector {
+    pub fn new() -> ConstructionErrorCollector {
+        ConstructionErrorCollector::default()
+    }
+
+    /// A sink recording into this collector, to be passed to `init_construction_error_sink`.
+    pub fn sink(&self) -> ConstructionErrorSink {
+        let errors = self.errors.dupe();
+        Box::new(
+            move |target: Option<&ConfiguredTargetLabel>, err: &ProviderCollectionError| {
+                errors.lock().unwrap().push(CollectedConstructionError {
+                    target: target.cloned(),
+                    message: err.to_string(),
+                });
+            },
+        )
+    }
+
+    /// The errors recorded so far, in the order they happened. Empties the collector.
+    pub fn drain(&self) -> Vec<CollectedConstructionError> {
+        mem::take(&mut *self.errors.lock().unwrap())
+    }
+}
+
+/// How many times each Buck1 flavor was rejected by `FrozenProviderCollectionValue::lookup_inner`.
+static REJECTED_FLAVORS: Lazy<Mutex<HashMap<String, usize>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Every flavor rejected with `UnknownFlavors` since the last `reset_rejected_flavors`, with how
+/// many times it was, most frequent first. Used to prioritize which flavors to map.
+pub fn rejected_flavors() -> Vec<(String, usize)> {
+    let mut flavors: Vec<(String, usize)> = REJECTED_FLAVORS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(flavor, count)| (flavor.clone(), *count))
+        .collect();
+    flavors.sort_by(|(a_flavor, a_count), (b_flavor, b_count)| {
+        b_count.cmp(a_count).then_with(|| a_flavor.cmp(b_flavor))
+    });
+    flavors
+}
+
+pub fn reset_rejected_flavors() {
+    REJECTED_FLAVORS.lock().unwrap().clear();
 }
 
 /// Holds a collection of `UserProvider`s. These can be accessed in Starlark by indexing on
@@ -110,10 +363,12 @@ enum ProviderCollectionError {
 /// ```
 ///
 /// This is the result of all UDR implementation functions
-#[derive(Debug, ProvidesStaticType, Allocative)]
+#[derive(ProvidesStaticType, Allocative)]
 #[repr(C)]
 pub struct ProviderCollectionGen<V> {
-    pub(crate) providers: SmallMap<Arc<ProviderId>, V>,
+    pub(crate) providers: SmallMap<InternedProviderId, V>,
+    /// Whether `DefaultInfo` was filled in by buck2 rather than returned by the rule.
+    pub(crate) default_info_synthesized: bool,
 }
 
 // Can't derive this since no instance for Arc
@@ -135,6 +390,18 @@ impl<V: Display> Display for ProviderCollectionGen<V> {
     }
 }
 
+/// Prints provider names and values in their Starlark `repr` form, which, unlike the derived
+/// implementation, doesn't include any addresses.
+impl<'v, V: ValueLike<'v>> Debug for ProviderCollectionGen<V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut s = f.debug_struct("ProviderCollection");
+        for (id, v) in &self.providers {
+            s.field(&id.name, &format_args!("{}", v.to_value()));
+        }
+        s.finish()
+    }
+}
+
 impl<'v, V: ValueLike<'v>> Serialize for ProviderCollectionGen<V> {
     fn serialize<S>(&self, s: S) -> Result<S::Ok, S::Error>
     where
@@ -146,21 +413,48 @@ impl<'v, V: ValueLike<'v>> Serialize for ProviderCollectionGen<V> {
 
 /// Provider collection access operator.
 #[derive(derive_more::Display, Debug)]
-enum GetOp {
+pub enum GetOp {
     #[display(fmt = "[]")]
     At,
     #[display(fmt = "in")]
     In,
     #[display(fmt = ".get")]
     Get,
+    #[display(fmt = ".assert_only")]
+    AssertOnly,
+    #[display(fmt = "-")]
+    Sub,
+    #[display(fmt = ".field_names")]
+    FieldNames,
 }
 
 impl<'v, V: ValueLike<'v>> ProviderCollectionGen<V> {
     /// Create most of the collection but don't do final assembly, or validate DefaultInfo here.
     /// This is an internal detail
     fn try_from_value_impl(
+        value: Value<'v>,
+        dedup_identical: bool,
+    ) -> anyhow::Result<SmallMap<InternedProviderId, Value<'v>>> {
+        match CONSTRUCTION_OBSERVER.get() {
+            None => Self::collect_providers(value, dedup_identical),
+            Some(observer) => {
+                let start = Instant::now();
+                let providers = Self::collect_providers(value, dedup_identical)?;
+                observer(&CollectionConstructionTiming {
+                    provider_count: providers.len(),
+                    duration: start.elapsed(),
+                });
+                Ok(providers)
+            }
+        }
+    }
+
+    /// If `dedup_identical` is set, a provider specified twice with equal values is kept once
+    /// rather than being an error.
+    fn collect_providers(
         mut value: Value<'v>,
-    ) -> anyhow::Result<SmallMap<Arc<ProviderId>, Value<'v>>> {
+        dedup_identical: bool,
+    ) -> anyhow::Result<SmallMap<InternedProviderId, Value<'v>>> {
         // Sometimes we might have a resolved promise here, in which case see through that
         value = StarlarkPromise::get_recursive(value);
 
@@ -178,7 +472,24 @@ impl<'v, V: ValueLike<'v>> ProviderCollectionGen<V> {
         for value in list.iter() {
             match value.as_provider() {
                 Some(provider) => {
-                    if let Some(existing_value) = providers.insert(provider.id().dupe(), value) {
+                    let id = InternedProviderId::new(provider.id());
+                    if let Some(deprecation) = provider_deprecation(id) {
+                        soft_error!(
+                            "deprecated_provider",
+                            ProviderCollectionError::DeprecatedProvider(
+                                DeprecationMessage {
+                                    name: &id.name,
+                                    deprecation: &deprecation,
+                                }
+                                .to_string()
+                            )
+                            .into()
+                        )?;
+                    }
+                    if let Some(existing_value) = providers.insert(id, value) {
+                        if dedup_identical && existing_value.equals(value)? {
+                            continue;
+                        }
                         return Err(ProviderCollectionError::CollectionSpecifiedProviderTwice {
                             provider_name: provider.id().name.clone(),
                             original_repr: existing_value.to_repr(),
@@ -199,6 +510,56 @@ impl<'v, V: ValueLike<'v>> ProviderCollectionGen<V> {
         Ok(providers)
     }
 
+    /// Run the checks of `try_from_value` without building the collection, returning every
+    /// problem found rather than only the first one. Used to lint rule implementations.
+    pub fn validate(value: Value<'v>) -> Result<(), Vec<ProviderCollectionError>> {
+        let value = StarlarkPromise::get_recursive(value);
+        let list = match ListRef::from_value(value) {
+            Some(v) => v,
+            None => {
+                return Err(vec![ProviderCollectionError::CollectionNotAList {
+                    repr: value.to_repr(),
+                }]);
+            }
+        };
+
+        let mut errors = Vec::new();
+        let mut seen: SmallMap<InternedProviderId, Value<'v>> = SmallMap::new();
+        for v in list.iter() {
+            match v.as_provider() {
+                Some(provider) => {
+                    let id = InternedProviderId::new(provider.id());
+                    match seen.get(&id) {
+                        Some(existing_value) => {
+                            errors.push(ProviderCollectionError::CollectionSpecifiedProviderTwice {
+                                provider_name: id.name.clone(),
+                                original_repr: existing_value.to_repr(),
+                                new_repr: v.to_repr(),
+                            })
+                        }
+                        None => {
+                            seen.insert(id, v);
+                        }
+                    }
+                }
+                None => errors.push(ProviderCollectionError::CollectionElementNotAProvider {
+                    repr: v.to_repr(),
+                }),
+            }
+        }
+        if !seen.contains_key(&DefaultInfoCallable::provider_id_t().interned()) {
+            errors.push(ProviderCollectionError::CollectionMissingDefaultInfo {
+                repr: value.to_repr(),
+            });
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
     /// Takes a value, e.g. a return from a `rule()` implementation function, and builds a `ProviderCollection` from it.
     ///
     /// An error is returned if:
@@ -206,15 +567,84 @@ impl<'v, V: ValueLike<'v>> ProviderCollectionGen<V> {
     ///  - Two instances of the same provider are provided
     ///  - `DefaultInfo` is not provided
     pub fn try_from_value(value: Value<'v>) -> anyhow::Result<ProviderCollection<'v>> {
-        let providers = Self::try_from_value_impl(value)?;
-        if !providers.contains_key(DefaultInfoCallable::provider_id()) {
-            return Err(ProviderCollectionError::CollectionMissingDefaultInfo {
+        Self::try_from_value_checked(value, false, DefaultInfoCallable::provider_id(), None)
+    }
+
+    /// Like `try_from_value`, but requires the provider `required_id` rather than `DefaultInfo`.
+    /// This is for tests exercising construction with their own providers: the collection may
+    /// have no `DefaultInfo`, so it is only available to tests.
+    #[cfg(test)]
+    pub(crate) fn try_from_value_with_required(
+        value: Value<'v>,
+        required_id: &ProviderId,
+    ) -> anyhow::Result<ProviderCollection<'v>> {
+        Self::try_from_value_checked(value, false, required_id, None)
+    }
+
+    /// Like `try_from_value`, for the providers returned by the rule of `target`. Errors passed
+    /// to the sink set by `init_construction_error_sink` are attributed to `target`.
+    pub fn try_from_value_for_target(
+        value: Value<'v>,
+        target: &ConfiguredTargetLabel,
+    ) -> anyhow::Result<ProviderCollection<'v>> {
+        Self::try_from_value_checked(
+            value,
+            false,
+            DefaultInfoCallable::provider_id(),
+            Some(target),
+        )
+    }
+
+    /// Like `try_from_value`, but the same provider specified twice is accepted if both values
+    /// are equal, e.g. when generated code appends the same provider instance twice.
+    /// Conflicting values for the same provider are still an error.
+    pub fn try_from_value_dedup_identical(
+        value: Value<'v>,
+    ) -> anyhow::Result<ProviderCollection<'v>> {
+        Self::try_from_value_checked(value, true, DefaultInfoCallable::provider_id(), None)
+    }
+
+    fn try_from_value_checked(
+        value: Value<'v>,
+        dedup_identical: bool,
+        required_id: &ProviderId,
+        target: Option<&ConfiguredTargetLabel>,
+    ) -> anyhow::Result<ProviderCollection<'v>> {
+        let res = Self::try_from_value_unreported(value, dedup_identical, required_id);
+        if let Err(e) = &res {
+            report_construction_error(target, e);
+        }
+        res
+    }
+
+    fn try_from_value_unreported(
+        value: Value<'v>,
+        dedup_identical: bool,
+        required_id: &ProviderId,
+    ) -> anyhow::Result<ProviderCollection<'v>> {
+        let providers = Self::try_from_value_impl(value, dedup_identical)?;
+        let has_required = match InternedProviderId::get(required_id) {
+            Some(id) => providers.contains_key(&id),
+            None => false,
+        };
+        if !has_required {
+            if required_id == &**DefaultInfoCallable::provider_id() {
+                return Err(ProviderCollectionError::CollectionMissingDefaultInfo {
+                    repr: value.to_repr(),
+                }
+                .into());
+            }
+            return Err(ProviderCollectionError::CollectionMissingRequiredProvider {
+                provider_name: required_id.name.clone(),
                 repr: value.to_repr(),
             }
             .into());
         }
 
-        Ok(ProviderCollection::<'v> { providers })
+        Ok(ProviderCollection::<'v> {
+            providers,
+            default_info_synthesized: false,
+        })
     }
 
     /// Takes a value, e.g. a return from a `rule()` implementation function, and builds a `ProviderCollection` from it.
@@ -228,9 +658,22 @@ impl<'v, V: ValueLike<'v>> ProviderCollectionGen<V> {
         value: Value<'v>,
         default_info_creator: impl FnOnce() -> Value<'v>,
     ) -> anyhow::Result<ProviderCollection<'v>> {
-        let mut providers = Self::try_from_value_impl(value)?;
+        let res = Self::try_from_value_with_default_info_unreported(value, default_info_creator);
+        if let Err(e) = &res {
+            report_construction_error(None, e);
+        }
+        res
+    }
+
+    fn try_from_value_with_default_info_unreported(
+        value: Value<'v>,
+        default_info_creator: impl FnOnce() -> Value<'v>,
+    ) -> anyhow::Result<ProviderCollection<'v>> {
+        let mut providers = Self::try_from_value_impl(value, false)?;
 
-        if !providers.contains_key(DefaultInfoCallable::provider_id()) {
+        let default_info_synthesized =
+            !providers.contains_key(&DefaultInfoCallable::provider_id_t().interned());
+        if default_info_synthesized {
             let di_value = default_info_creator();
             if DefaultInfo::from_value(di_value).is_none() {
                 return Err(ProviderCollectionError::ValueIsNotDefaultInfo {
@@ -238,9 +681,45 @@ impl<'v, V: ValueLike<'v>> ProviderCollectionGen<V> {
                 }
                 .into());
             }
-            providers.insert(DefaultInfoCallable::provider_id().dupe(), di_value);
+            providers.insert(DefaultInfoCallable::provider_id_t().interned(), di_value);
+        }
+        Ok(ProviderCollection::<'v> {
+            providers,
+            default_info_synthesized,
+        })
+    }
+
+    /// Look up a provider by an id which may not have been interned.
+    fn get_by_id(&self, provider_id: &ProviderId) -> Option<&V> {
+        self.providers.get(&InternedProviderId::get(provider_id)?)
+    }
+
+    /// Whether `DefaultInfo` was created by `try_from_value_with_default_info` because the
+    /// rule did not return one.
+    pub fn was_default_info_synthesized(&self) -> bool {
+        self.default_info_synthesized
+    }
+
+    /// Whether `list` contains exactly the providers of this collection with equal values, in any
+    /// order. A `DefaultInfo` synthesized by buck2 may be omitted from the list.
+    fn equals_provider_list(&self, list: &ListRef<'v>) -> anyhow::Result<bool> {
+        let mut seen = HashSet::new();
+        for value in list.iter() {
+            let id = match value.as_provider() {
+                Some(provider) => InternedProviderId::new(provider.id()),
+                None => return Ok(false),
+            };
+            if !seen.insert(id) {
+                return Ok(false);
+            }
+            match self.providers.get(&id) {
+                Some(v) if v.to_value().equals(value)? => {}
+                _ => return Ok(false),
+            }
         }
-        Ok(ProviderCollection::<'v> { providers })
+        let default_info_omitted = self.default_info_synthesized
+            && !seen.contains(&DefaultInfoCallable::provider_id_t().interned());
+        Ok(seen.len() + default_info_omitted as usize == self.providers.len())
     }
 
     /// Common implementation of `[]`, `in`, and `.get`.
@@ -248,16 +727,19 @@ impl<'v, V: ValueLike<'v>> ProviderCollectionGen<V> {
         &self,
         index: Value<'v>,
         op: GetOp,
-    ) -> anyhow::Result<Either<Value<'v>, Arc<ProviderId>>> {
+    ) -> anyhow::Result<Either<Value<'v>, InternedProviderId>> {
         match index.as_provider_callable() {
             Some(callable) => {
-                let provider_id = callable.require_id()?;
+                let provider_id = callable.require_interned_id()?;
                 match self.providers.get(&provider_id) {
                     Some(v) => Ok(Either::Left(v.to_value())),
-                    None => Ok(Either::Right(provider_id)),
+                    None => match synthesize_provider(provider_id)? {
+                        Some(v) => Ok(Either::Left(v.to_value())),
+                        None => Ok(Either::Right(provider_id)),
+                    },
                 }
             }
-            None => Err(ProviderCollectionError::AtTypeNotProvider(op, index.get_type()).into()),
+            None => Err(index_not_a_provider(op, index, strict_string_index()).into()),
         }
     }
 
@@ -265,6 +747,144 @@ impl<'v, V: ValueLike<'v>> ProviderCollectionGen<V> {
     pub(crate) fn get(&self, index: Value<'v>) -> anyhow::Result<Value<'v>> {
         Ok(self.get_impl(index, GetOp::Get)?.left_or(Value::new_none()))
     }
+
+    /// The providers with an index in `start..stop`, in collection order, along with
+    /// `DefaultInfo` wherever it is so that the result is a valid collection. Negative indices
+    /// count from the end, and out of range indices are clamped.
+    pub fn slice_range(&self, start: Option<i32>, stop: Option<i32>) -> ProviderCollection<'v> {
+        let len = self.providers.len() as i32;
+        let clamp = |i: i32| (if i < 0 { len + i } else { i }).clamp(0, len) as usize;
+        let range = start.map_or(0, clamp)..stop.map_or(len as usize, clamp);
+        ProviderCollection {
+            providers: self
+                .providers
+                .iter()
+                .enumerate()
+                .filter(|(i, (id, _))| {
+                    range.contains(i) || **id == DefaultInfoCallable::provider_id_t().interned()
+                })
+                .map(|(_, (id, v))| (*id, v.to_value()))
+                .collect(),
+            default_info_synthesized: self.default_info_synthesized,
+        }
+    }
+
+    /// A copy of this collection with `provider` added. If the collection already has a provider
+    /// of the same type, it's an error unless `replace` is set, in which case `provider` takes
+    /// its place.
+    pub fn with_provider(
+        &self,
+        provider: Value<'v>,
+        replace: bool,
+    ) -> anyhow::Result<ProviderCollection<'v>> {
+        let id = match provider.as_provider() {
+            Some(p) => InternedProviderId::new(p.id()),
+            None => {
+                return Err(ProviderCollectionError::CollectionElementNotAProvider {
+                    repr: provider.to_repr(),
+                }
+                .into());
+            }
+        };
+        let mut providers: SmallMap<InternedProviderId, Value<'v>> = self
+            .providers
+            .iter()
+            .map(|(id, v)| (*id, v.to_value()))
+            .collect();
+        if let Some(existing_value) = providers.insert(id, provider) {
+            if !replace {
+                return Err(ProviderCollectionError::CollectionSpecifiedProviderTwice {
+                    provider_name: id.name.clone(),
+                    original_repr: existing_value.to_repr(),
+                    new_repr: provider.to_repr(),
+                }
+                .into());
+            }
+        }
+        Ok(ProviderCollection {
+            providers,
+            default_info_synthesized: self.default_info_synthesized
+                && id != DefaultInfoCallable::provider_id_t().interned(),
+        })
+    }
+
+    /// A copy of this collection without the provider `provider_id`. Removing a provider which
+    /// is not present is a no-op, but `DefaultInfo` can't be removed.
+    pub fn without(&self, provider_id: &ProviderId) -> anyhow::Result<ProviderCollection<'v>> {
+        if provider_id == &**DefaultInfoCallable::provider_id() {
+            return Err(ProviderCollectionError::CannotRemoveDefaultInfo.into());
+        }
+        Ok(ProviderCollection {
+            providers: self
+                .providers
+                .iter()
+                .filter(|(id, _)| ***id != *provider_id)
+                .map(|(id, v)| (*id, v.to_value()))
+                .collect(),
+            default_info_synthesized: self.default_info_synthesized,
+        })
+    }
+
+    /// Fail if the collection contains any provider other than `DefaultInfo` and the provider
+    /// types in `allowed`.
+    pub(crate) fn assert_only(&self, allowed: &[Value<'v>]) -> anyhow::Result<()> {
+        let allowed = allowed
+            .iter()
+            .map(|v| match v.as_provider_callable() {
+                Some(callable) => Ok(InternedProviderId::new(&callable.require_id()?)),
+                None => Err(ProviderCollectionError::AtTypeNotProvider(
+                    GetOp::AssertOnly,
+                    v.get_type(),
+                )
+                .into()),
+            })
+            .collect::<anyhow::Result<SmallSet<_>>>()?;
+        let default_info = DefaultInfoCallable::provider_id_t().interned();
+        let unexpected: Vec<String> = self
+            .providers
+            .keys()
+            .filter(|id| **id != default_info && !allowed.contains(*id))
+            .map(|id| id.name.clone())
+            .collect();
+        if !unexpected.is_empty() {
+            return Err(ProviderCollectionError::UnexpectedProviders(unexpected).into());
+        }
+        Ok(())
+    }
+
+    /// The declared field names of the provider of type `index` in this collection.
+    pub(crate) fn field_names(&self, index: Value<'v>) -> anyhow::Result<Vec<String>> {
+        match self.get_impl(index, GetOp::FieldNames)? {
+            Either::Left(v) => Ok(v
+                .as_provider()
+                .expect("provider collections only contain providers")
+                .items()
+                .into_iter()
+                .map(|(name, _)| name.to_owned())
+                .collect()),
+            Either::Right(provider_id) => Err(ProviderCollectionError::AtNotFound(
+                provider_id.name.clone(),
+                self.providers.keys().map(|k| k.name.clone()).collect(),
+            )
+            .into()),
+        }
+    }
+
+    /// Like `get`, but a missing provider is reported as a soft error. Used while migrating rules
+    /// to always return a provider their dependents expect.
+    pub(crate) fn get_lenient(&self, index: Value<'v>) -> anyhow::Result<Value<'v>> {
+        match self.get_impl(index, GetOp::Get)? {
+            Either::Left(v) => Ok(v),
+            Either::Right(provider_id) => {
+                soft_error!(
+                    "expected_provider_missing",
+                    ProviderCollectionError::ExpectedProviderMissing(provider_id.name.clone())
+                        .into()
+                )?;
+                Ok(Value::new_none())
+            }
+        }
+    }
 }
 
 #[starlark_module]
@@ -272,6 +892,61 @@ fn provider_collection_methods(builder: &mut MethodsBuilder) {
     fn get<'v>(this: &ProviderCollection<'v>, index: Value<'v>) -> anyhow::Result<Value<'v>> {
         this.get(index)
     }
+
+    /// Like `get`, but if the provider is missing, returns the result of calling `fallback` with
+    /// no arguments instead of `None`. `fallback` is only called on a miss, so it can be
+    /// expensive, e.g. `c.get_or(FooInfo, lambda: FooInfo(foo = compute()))`.
+    fn get_or<'v>(
+        this: &ProviderCollection<'v>,
+        index: Value<'v>,
+        fallback: Value<'v>,
+        eval: &mut Evaluator<'v, '_>,
+    ) -> anyhow::Result<Value<'v>> {
+        match this.get_impl(index, GetOp::Get)? {
+            Either::Left(v) => Ok(v),
+            Either::Right(_) => eval.eval_function(fallback, &[], &[]),
+        }
+    }
+
+    /// Like `get`, but reports a soft error if the provider is missing.
+    fn get_warn<'v>(this: &ProviderCollection<'v>, index: Value<'v>) -> anyhow::Result<Value<'v>> {
+        this.get_lenient(index)
+    }
+
+    /// The declared field names of a provider type present in this collection.
+    fn field_names<'v>(
+        this: &ProviderCollection<'v>,
+        index: Value<'v>,
+    ) -> anyhow::Result<Vec<String>> {
+        this.field_names(index)
+    }
+
+    /// Whether `DefaultInfo` was filled in by buck2 rather than provided explicitly, e.g. for
+    /// a sub-target given as a list of providers without a `DefaultInfo`.
+    fn default_info_is_synthesized<'v>(this: &ProviderCollection<'v>) -> anyhow::Result<bool> {
+        Ok(this.was_default_info_synthesized())
+    }
+
+    /// A new collection with `provider` added. Fails if the collection already has a provider
+    /// of the same type, unless `override = True` is passed, in which case `provider` replaces
+    /// it.
+    fn with<'v>(
+        this: &ProviderCollection<'v>,
+        provider: Value<'v>,
+        #[starlark(require = named, default = false)] r#override: bool,
+        heap: &'v Heap,
+    ) -> anyhow::Result<Value<'v>> {
+        Ok(heap.alloc(this.with_provider(provider, r#override)?))
+    }
+
+    /// Fails if the collection contains providers other than `DefaultInfo` and those listed.
+    fn assert_only<'v>(
+        this: &ProviderCollection<'v>,
+        allowed: Vec<Value<'v>>,
+    ) -> anyhow::Result<NoneType> {
+        this.assert_only(&allowed)?;
+        Ok(NoneType)
+    }
 }
 
 impl<'v, V: ValueLike<'v> + 'v> StarlarkValue<'v> for ProviderCollectionGen<V>
@@ -295,6 +970,58 @@ where
         Ok(self.get_impl(other, GetOp::In)?.is_left())
     }
 
+    /// Collections are equal if they contain equal values for the same providers, in any order.
+    fn equals(&self, other: Value<'v>) -> anyhow::Result<bool> {
+        let other = match ProviderCollection::from_value(other) {
+            Some(other) => other,
+            None => return Ok(false),
+        };
+        if self.providers.len() != other.providers.len() {
+            return Ok(false);
+        }
+        for (id, v) in &self.providers {
+            match other.providers.get(id) {
+                Some(other_v) if v.to_value().equals(*other_v)? => {}
+                _ => return Ok(false),
+            }
+        }
+        Ok(true)
+    }
+
+    fn slice(
+        &self,
+        start: Option<Value<'v>>,
+        stop: Option<Value<'v>>,
+        stride: Option<Value<'v>>,
+        heap: &'v Heap,
+    ) -> anyhow::Result<Value<'v>> {
+        if stride.map_or(false, |s| !s.is_none()) {
+            return Err(ProviderCollectionError::SliceWithStep.into());
+        }
+        let bound = |v: Option<Value<'v>>| match v {
+            Some(v) if !v.is_none() => match v.unpack_int() {
+                Some(i) => Ok(Some(i)),
+                None => Err(ProviderCollectionError::SliceBoundNotAnInt(v.to_repr())),
+            },
+            _ => Ok(None),
+        };
+        Ok(heap.alloc(self.slice_range(bound(start)?, bound(stop)?)))
+    }
+
+    fn sub(&self, other: Value<'v>, heap: &'v Heap) -> anyhow::Result<Value<'v>> {
+        let provider_id = match other.as_provider_callable() {
+            Some(callable) => callable.require_id()?,
+            None => {
+                return Err(ProviderCollectionError::AtTypeNotProvider(
+                    GetOp::Sub,
+                    other.get_type(),
+                )
+                .into());
+            }
+        };
+        Ok(heap.alloc(self.without(&provider_id)?))
+    }
+
     fn get_methods() -> Option<&'static Methods>
     where
         Self: Sized,
@@ -310,22 +1037,41 @@ unsafe impl<'v> Trace<'v> for ProviderCollection<'v> {
     }
 }
 
+fn check_frozen_default_info(value: FrozenValue) -> anyhow::Result<()> {
+    if value.downcast_frozen_ref::<FrozenDefaultInfo>().is_none() {
+        return Err(ProviderCollectionError::ValueIsNotDefaultInfo {
+            repr: value.to_value().to_repr(),
+        }
+        .into());
+    }
+    Ok(())
+}
+
 impl<'v> Freeze for ProviderCollection<'v> {
     type Frozen = FrozenProviderCollection;
     fn freeze(self, freezer: &Freezer) -> anyhow::Result<Self::Frozen> {
-        let providers = self
+        let providers: SmallMap<InternedProviderId, FrozenValue> = self
             .providers
             .into_iter()
             .map(|(k, v)| anyhow::Ok((k, freezer.freeze(v)?)))
             .collect::<anyhow::Result<_>>()?;
-        Ok(FrozenProviderCollection { providers })
+        // `FrozenProviderCollection::default_info` assumes this, fail now rather than on first
+        // access.
+        if let Some(default_info) = providers.get(&DefaultInfoCallable::provider_id_t().interned())
+        {
+            check_frozen_default_info(*default_info)?;
+        }
+        Ok(FrozenProviderCollection {
+            providers,
+            default_info_synthesized: self.default_info_synthesized,
+        })
     }
 }
 
 impl<'v> ProviderCollection<'v> {
     pub fn default_info(&self) -> FrozenRef<'static, FrozenDefaultInfo> {
         self.providers
-            .get(DefaultInfoCallable::provider_id())
+            .get(&DefaultInfoCallable::provider_id_t().interned())
             .expect("DefaultInfo should always be set")
             .unpack_frozen()
             .expect("Provider collections are always frozen")
@@ -334,6 +1080,49 @@ impl<'v> ProviderCollection<'v> {
     }
 }
 
+/// A `ProviderCollection` whose provider list is produced by a closure that only runs the first
+/// time the collection is accessed. The result, including any validation failure, is cached.
+pub struct LazyProviderCollection<'v> {
+    producer: Cell<Option<Box<dyn FnOnce() -> anyhow::Result<Value<'v>> + 'v>>>,
+    collection: once_cell::unsync::OnceCell<SharedResult<ProviderCollection<'v>>>,
+}
+
+impl<'v> LazyProviderCollection<'v> {
+    pub fn new(producer: impl FnOnce() -> anyhow::Result<Value<'v>> + 'v) -> Self {
+        Self {
+            producer: Cell::new(Some(Box::new(producer))),
+            collection: once_cell::unsync::OnceCell::new(),
+        }
+    }
+
+    /// Run the producer if it hasn't run yet, and build the collection from its result,
+    /// enforcing the same invariants as `ProviderCollection::try_from_value`.
+    pub fn force(&self) -> anyhow::Result<&ProviderCollection<'v>> {
+        let collection = self.collection.get_or_init(|| {
+            let producer = self
+                .producer
+                .take()
+                .expect("producer is only taken when initializing the collection");
+            producer()
+                .and_then(ProviderCollection::try_from_value)
+                .shared_error()
+        });
+        match collection {
+            Ok(collection) => Ok(collection),
+            Err(e) => Err(e.dupe().into()),
+        }
+    }
+
+    /// Implementation of `.get`, materializing the collection if needed.
+    pub fn get(&self, index: Value<'v>) -> anyhow::Result<Value<'v>> {
+        self.force()?.get(index)
+    }
+
+    pub fn contains_provider(&self, provider_id: &ProviderId) -> anyhow::Result<bool> {
+        Ok(self.force()?.get_by_id(provider_id).is_some())
+    }
+}
+
 impl FrozenProviderCollection {
     pub fn default_info(&self) -> FrozenRef<'static, FrozenDefaultInfo> {
         self.get_provider(DefaultInfoCallable::provider_id_t())
@@ -343,398 +1132,4447 @@ impl FrozenProviderCollection {
     pub fn default_info_value(&self) -> FrozenValue {
         *self
             .providers
-            .get(DefaultInfoCallable::provider_id())
+            .get(&DefaultInfoCallable::provider_id_t().interned())
             .expect("DefaultInfo should always be set")
     }
 
-    pub fn contains_provider(&self, provider_id: &ProviderId) -> bool {
-        self.providers.contains_key(provider_id)
+    /// The default outputs of `DefaultInfo`, in order.
+    pub fn default_artifacts(&self) -> Vec<Artifact> {
+        self.default_info()
+            .default_outputs()
+            .iter()
+            .map(|output| output.artifact())
+            .collect()
     }
 
-    pub fn get_provider<T: StarlarkValue<'static>>(
-        &self,
-        provider_id: &ProviderIdWithType<T>,
-    ) -> Option<FrozenRef<'static, T>> {
-        self.providers
-            .get(provider_id.id())
-            .and_then(|v| v.downcast_frozen_ref::<T>())
+    /// Whether this collection has providers defined in a `.bzl` file, but its `DefaultInfo` has
+    /// neither default outputs nor sub-targets. That's usually a rule that forgot to set its
+    /// outputs, which a linter can warn about.
+    pub fn has_providers_but_no_outputs(&self) -> bool {
+        let default_info = self.default_info();
+        self.providers.keys().any(|id| id.path.is_some())
+            && default_info.default_outputs().is_empty()
+            && default_info.sub_targets().is_empty()
     }
 
-    pub fn get_provider_raw(&self, provider_id: &ProviderId) -> Option<&FrozenValue> {
-        self.providers.get(provider_id)
+    /// The names in `expected` that are not sub-targets of this collection, in the order given,
+    /// e.g. to catch a rule that forgot to populate a documented sub-target.
+    pub fn missing_declared_subtargets(&self, expected: &[&str]) -> Vec<String> {
+        let sub_targets = self.default_info().sub_targets();
+        expected
+            .iter()
+            .filter(|name| !sub_targets.contains_key(*name))
+            .map(|name| (*name).to_owned())
+            .collect()
     }
 
-    pub fn provider_names(&self) -> Vec<String> {
-        self.providers.keys().map(|k| k.name.to_owned()).collect()
+    /// A view of this collection for human-facing output, which shows sub-targets that only
+    /// wrap a single output as that output. See `SimplifiedProviderCollection`.
+    pub fn simplify(&self) -> SimplifiedProviderCollection<'_> {
+        SimplifiedProviderCollection { collection: self }
     }
 
-    pub fn provider_ids(&self) -> Vec<&ProviderId> {
-        self.providers.keys().map(|k| &**k).collect()
+    /// The only output of this collection, if it is nothing but a `DefaultInfo` with a single
+    /// default output, no other outputs and no sub-targets.
+    fn single_output(&self) -> Option<FrozenRef<'static, StarlarkArtifact>> {
+        if self.providers.len() != 1 {
+            return None;
+        }
+        let default_info = self.default_info();
+        if !default_info.sub_targets().is_empty()
+            || !ListRef::from_frozen_value(default_info.other_outputs_raw())
+                .map_or(false, |other_outputs| other_outputs.is_empty())
+        {
+            return None;
+        }
+        match default_info.default_outputs().as_slice() {
+            [output] => Some(*output),
+            _ => None,
+        }
     }
-}
 
-/// Thin wrapper around `FrozenValue` that can only be constructed if that value is a `FrozenProviderCollection`
-#[derive(Debug, Clone, Dupe, Allocative)]
-pub struct FrozenProviderCollectionValue {
-    #[allocative(skip)] // TODO(nga): do not skip.
-    value: OwnedFrozenValueTyped<FrozenProviderCollection>,
-}
+    /// Build a new collection from the providers for which `keep` returns true. `DefaultInfo` is
+    /// always kept so that the result is a valid collection. Every method returning a subset of
+    /// this collection should go through this, so that the original provider order is preserved.
+    fn filtered(
+        &self,
+        keep: impl Fn(&ProviderId, FrozenValue) -> bool,
+    ) -> FrozenProviderCollection {
+        FrozenProviderCollection {
+            providers: self
+                .providers
+                .iter()
+                .filter(|(id, v)| {
+                    **id == DefaultInfoCallable::provider_id_t().interned() || keep(id, **v)
+                })
+                .map(|(id, v)| (id.dupe(), *v))
+                .collect(),
+            default_info_synthesized: self.default_info_synthesized,
+        }
+    }
 
-impl Serialize for FrozenProviderCollectionValue {
-    fn serialize<S>(&self, s: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        (*self.value).serialize(s)
+    /// Check that the sub-targets of `DefaultInfo` are all provider collections, recursively.
+    /// Sub-target lookups assume they are, so this catches rules producing malformed ones.
+    pub fn validate_subtargets(&self) -> anyhow::Result<()> {
+        fn validate(
+            collection: &FrozenProviderCollection,
+            path: &mut String,
+        ) -> anyhow::Result<()> {
+            let sub_targets = collection.default_info().sub_targets_raw();
+            let sub_targets = match FrozenDictRef::from_frozen_value(sub_targets) {
+                Some(sub_targets) => sub_targets,
+                None => {
+                    return Err(ProviderCollectionError::SubTargetsNotADict {
+                        path: path.clone(),
+                        repr: sub_targets.to_value().to_repr(),
+                    }
+                    .into());
+                }
+            };
+            for (name, sub_target) in sub_targets.iter() {
+                let len = path.len();
+                path.push_str(&format!("[{}]", name.to_value().to_str()));
+                match sub_target.downcast_ref::<FrozenProviderCollection>() {
+                    Some(sub_target) => validate(sub_target, path)?,
+                    None => {
+                        return Err(ProviderCollectionError::MalformedSubTarget {
+                            path: path.clone(),
+                            repr: sub_target.to_value().to_repr(),
+                        }
+                        .into());
+                    }
+                }
+                path.truncate(len);
+            }
+            Ok(())
+        }
+
+        validate(self, &mut String::new())
     }
-}
 
-impl FrozenProviderCollectionValue {
-    pub fn from_value(value: OwnedFrozenValueTyped<FrozenProviderCollection>) -> Self {
-        Self { value }
+    /// Call `f` with this collection and, depth first, each collection in its sub-target tree,
+    /// along with the names of the sub-targets leading to it. A collection shared by several
+    /// sub-targets is visited under each of them, but frozen sub-target collections can't
+    /// normally form a cycle, so one already being visited is skipped to make sure a malformed
+    /// one can't send us into an infinite loop.
+    fn walk_sub_targets<'a>(&'a self, mut f: impl FnMut(&[&'a str], &'a FrozenProviderCollection)) {
+        fn walk<'a>(
+            collection: &'a FrozenProviderCollection,
+            path: &mut Vec<&'a str>,
+            stack: &mut Vec<*const FrozenProviderCollection>,
+            f: &mut impl FnMut(&[&'a str], &'a FrozenProviderCollection),
+        ) {
+            let ptr = collection as *const _;
+            if stack.contains(&ptr) {
+                return;
+            }
+            stack.push(ptr);
+            f(path, collection);
+            for (name, sub_target) in collection.default_info().as_ref().sub_targets() {
+                path.push(name);
+                walk(sub_target.as_ref(), path, stack, f);
+                path.pop();
+            }
+            stack.pop();
+        }
+
+        walk(self, &mut Vec::new(), &mut Vec::new(), &mut f)
     }
 
-    pub fn try_from_value(value: OwnedFrozenValue) -> anyhow::Result<Self> {
-        Ok(Self {
-            value: value.downcast().map_err(|value| {
-                anyhow::anyhow!("{:?} was not a FrozenProviderCollection", value)
-            })?,
-        })
+    /// Check that no artifact is a default output of more than one collection in the sub-target
+    /// tree of this one, which would make materializing them conflict.
+    pub fn assert_outputs_unique(&self) -> anyhow::Result<()> {
+        fn describe(path: &[&str]) -> String {
+            if path.is_empty() {
+                "the collection".to_owned()
+            } else {
+                let path: String = path.iter().map(|name| format!("[{}]", name)).collect();
+                format!("sub-target `{}`", path)
+            }
+        }
+
+        let mut outputs = Vec::new();
+        self.walk_sub_targets(|path, collection| {
+            for output in collection.default_info().default_outputs() {
+                outputs.push((output.artifact(), path.to_vec()));
+            }
+        });
+
+        let mut seen: HashMap<Artifact, Vec<&str>> = HashMap::new();
+        for (artifact, path) in outputs {
+            if let Some(first) = seen.get(&artifact) {
+                return Err(ProviderCollectionError::DuplicateDefaultOutput {
+                    artifact: artifact.to_string(),
+                    first: describe(first),
+                    second: describe(&path),
+                }
+                .into());
+            }
+            seen.insert(artifact, path);
+        }
+        Ok(())
     }
 
-    pub fn value(&self) -> &OwnedFrozenValueTyped<FrozenProviderCollection> {
-        &self.value
+    /// Everywhere `provider_id` appears: `ProvidersName::Default` for this collection itself,
+    /// and the path of each sub-target having it, depth first. For users who can't find a
+    /// provider at the top level, this tells them which sub-target to ask for instead.
+    pub fn locate_provider(&self, provider_id: &ProviderId) -> Vec<ProvidersName> {
+        let mut res = Vec::new();
+        // No collection can contain a provider whose id was never interned.
+        if let Some(provider_id) = InternedProviderId::get(provider_id) {
+            self.walk_sub_targets(|path, collection| {
+                if collection.providers.contains_key(&provider_id) {
+                    res.push(path.iter().fold(ProvidersName::Default, |name, sub_name| {
+                        name.push(ProviderName::new_unchecked((*sub_name).to_owned()))
+                    }));
+                }
+            });
+        }
+        res
     }
 
-    pub fn provider_collection(&self) -> &FrozenProviderCollection {
-        self.value.as_ref()
+    /// Providers in this collection that expose nested provider collections. Currently only
+    /// `DefaultInfo` does, and only when it has sub-targets.
+    pub fn subtarget_bearing_providers(&self) -> Vec<&ProviderId> {
+        self.providers
+            .iter()
+            .filter(
+                |(_, v)| match v.downcast_frozen_ref::<FrozenDefaultInfo>() {
+                    Some(default_info) => !default_info.sub_targets().is_empty(),
+                    None => false,
+                },
+            )
+            .map(|(id, _)| &**id)
+            .collect()
     }
 
-    pub fn lookup_inner(&self, label: &ConfiguredProvidersLabel) -> anyhow::Result<Self> {
-        match label.name() {
-            ProvidersName::Default => anyhow::Ok(self.dupe()),
-            ProvidersName::NonDefault(box NonDefaultProvidersName::Named(provider_names)) => {
-                Ok(FrozenProviderCollectionValue::from_value(
-                    self.value().try_map(|v| {
-                        let mut collection_value = v;
+    pub fn contains_provider(&self, provider_id: &ProviderId) -> bool {
+        self.get_by_id(provider_id).is_some()
+    }
 
-                        for provider_name in &**provider_names {
-                            let maybe_di = collection_value
-                                .default_info()
-                                .get_sub_target_providers(provider_name.as_str());
+    /// Check that every provider required by `protocol` is present, returning the missing ones
+    /// otherwise.
+    pub fn conforms_to<'a>(
+        &self,
+        protocol: &'a ProviderProtocol,
+    ) -> Result<(), Vec<&'a ProviderId>> {
+        let missing: Vec<&ProviderId> = protocol
+            .required
+            .iter()
+            .filter(|id| !self.contains_provider(id))
+            .map(|id| &**id)
+            .collect();
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(missing)
+        }
+    }
 
-                            match maybe_di {
-                                // The inner values should all be frozen if in a frozen provider collection
-                                Some(inner) => {
-                                    collection_value = inner;
-                                }
-                                None => {
-                                    return Err(anyhow::anyhow!(
-                                        ProviderCollectionError::RequestedInvalidSubTarget(
-                                            provider_name.clone(),
-                                            label.clone(),
-                                            v.default_info()
-                                                .sub_targets()
-                                                .keys()
-                                                .map(|s| (*s).to_owned())
-                                                .collect()
-                                        )
-                                    ));
-                                }
-                            }
-                        }
+    /// Check this collection against `schema`, reporting every violation at once: required
+    /// providers which are missing, and fields which are missing or have the wrong type.
+    pub fn validate_against_schema(&self, schema: &ProviderSchema) -> anyhow::Result<()> {
+        let mut violations = Vec::new();
+        for expected in &schema.providers {
+            let provider = self
+                .providers
+                .iter()
+                .find(|(id, _)| id.name == expected.name)
+                .map(|(_, v)| v.as_provider().expect("collection values are providers"));
+            let provider = match provider {
+                Some(provider) => provider,
+                None => {
+                    violations.push(format!("missing provider `{}`", expected.name));
+                    continue;
+                }
+            };
+            for field in &expected.fields {
+                match (provider.get_field(&field.name), &field.ty) {
+                    (None, _) => violations
+                        .push(format!("`{}` has no field `{}`", expected.name, field.name)),
+                    (Some(value), Some(ty)) if value.get_type() != ty => violations.push(format!(
+                        "field `{}` of `{}` should be of type `{}`, got `{}`",
+                        field.name,
+                        expected.name,
+                        ty,
+                        value.get_type()
+                    )),
+                    (Some(_), _) => {}
+                }
+            }
+        }
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(ProviderCollectionError::SchemaViolations(violations).into())
+        }
+    }
 
-                        Ok(collection_value)
-                    })?,
-                ))
+    /// Fail if more than one provider from any of `groups` is present, e.g. to check that a
+    /// target doesn't expose both a static and a shared library.
+    pub fn assert_mutually_exclusive(&self, groups: &[&[&ProviderId]]) -> anyhow::Result<()> {
+        for group in groups {
+            let present: Vec<String> = group
+                .iter()
+                .filter(|id| self.contains_provider(id))
+                .map(|id| id.name.clone())
+                .collect();
+            if present.len() > 1 {
+                return Err(ProviderCollectionError::MutuallyExclusiveProviders(present).into());
             }
-            ProvidersName::NonDefault(box NonDefaultProvidersName::UnrecognizedFlavor(flavor)) => {
-                Err(ProviderCollectionError::UnknownFlavors {
-                    target: label.unconfigured().to_string(),
-                    flavor: (**flavor).to_owned(),
+        }
+        Ok(())
+    }
+
+    /// Fail if any provider in this collection is deprecated. Constructing the collection only
+    /// reports a soft error for those, so this lets validators block new usages once a
+    /// migration is done.
+    pub fn assert_no_deprecated(&self) -> anyhow::Result<()> {
+        let deprecated: Vec<String> = self
+            .providers
+            .keys()
+            .filter_map(|id| {
+                provider_deprecation(*id).map(|deprecation| {
+                    DeprecationMessage {
+                        name: &id.name,
+                        deprecation: &deprecation,
+                    }
+                    .to_string()
+                })
+            })
+            .collect();
+        if deprecated.is_empty() {
+            Ok(())
+        } else {
+            Err(ProviderCollectionError::DeprecatedProvidersPresent(deprecated).into())
+        }
+    }
+
+    /// Like `contains_provider`, but only compares pointers.
+    pub fn contains_interned_provider(&self, provider_id: InternedProviderId) -> bool {
+        self.providers.contains_key(&provider_id)
+    }
+
+    pub fn get_provider<T: StarlarkValue<'static>>(
+        &self,
+        provider_id: &ProviderIdWithType<T>,
+    ) -> Option<FrozenRef<'static, T>> {
+        self.providers
+            .get(&provider_id.interned())
+            .and_then(|v| v.downcast_frozen_ref::<T>())
+    }
+
+    pub fn get_provider_raw(&self, provider_id: &ProviderId) -> Option<&FrozenValue> {
+        self.get_by_id(provider_id)
+    }
+
+    pub fn get_interned_provider_raw(
+        &self,
+        provider_id: InternedProviderId,
+    ) -> Option<&FrozenValue> {
+        self.providers.get(&provider_id)
+    }
+
+    pub fn provider_names(&self) -> Vec<String> {
+        self.providers.keys().map(|k| k.name.to_owned()).collect()
+    }
+
+    pub fn provider_ids(&self) -> Vec<&ProviderId> {
+        self.providers.keys().map(|k| &**k).collect()
+    }
+
+    /// The ids of the providers in this collection as an ordered set, to compare the providers
+    /// of several collections with set operations.
+    pub fn provider_id_set(&self) -> BTreeSet<&ProviderId> {
+        self.providers.keys().map(|k| &**k).collect()
+    }
+
+    /// The callables of the providers in this collection, in provider order, e.g. to index the
+    /// collection again from Rust. Only builtin provider callables can be recovered from a
+    /// provider; the other providers are skipped with a soft error.
+    pub fn provider_callables(&self) -> Vec<FrozenValue> {
+        let mut res = Vec::with_capacity(self.providers.len());
+        for id in self.providers.keys() {
+            match builtin_provider_callable(*id) {
+                Some(callable) => res.push(callable),
+                None => {
+                    let _ignore = soft_error!(
+                        "provider_callable_unavailable",
+                        ProviderCollectionError::ProviderCallableUnavailable(id.name.clone())
+                            .into()
+                    );
                 }
-                .into())
             }
         }
+        res
     }
-}
 
-#[cfg(test)]
-pub(crate) mod tester {
-    use buck2_interpreter_for_build::provider::callable::ValueAsProviderCallableLike;
-    use dupe::Dupe;
-    use starlark::environment::GlobalsBuilder;
-    use starlark::values::Value;
-    use starlark::values::ValueLike;
+    /// Names of the providers in this collection, grouped by the `.bzl` file defining them.
+    /// Native providers have no defining file and are not included.
+    pub fn providers_by_file(&self) -> BTreeMap<String, Vec<String>> {
+        let mut res: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for id in self.providers.keys() {
+            if let Some(path) = &id.path {
+                res.entry(path.to_string())
+                    .or_default()
+                    .push(id.name.clone());
+            }
+        }
+        res
+    }
 
-    use crate::interpreter::rule_defs::provider::collection::FrozenProviderCollection;
-    use crate::interpreter::rule_defs::provider::ProviderCollection;
+    /// Providers in this collection defined in the `.bzl` file `path`, written like the keys of
+    /// `providers_by_file`, e.g. `root//my/rules.bzl`.
+    pub fn providers_from_file(&self, path: &str) -> Vec<&ProviderId> {
+        self.providers
+            .keys()
+            .filter(|id| match &id.path {
+                Some(p) => p.to_string() == path,
+                None => false,
+            })
+            .map(|id| &**id)
+            .collect()
+    }
 
-    #[starlark_module]
-    pub fn collection_creator(builder: &mut GlobalsBuilder) {
-        fn create_collection<'v>(value: Value<'v>) -> anyhow::Result<ProviderCollection<'v>> {
-            ProviderCollection::try_from_value(value)
+    /// The `.bzl` files defining the providers of this collection and, recursively, of all its
+    /// sub-targets, written like the keys of `providers_by_file`.
+    pub fn all_defining_files_recursive(&self) -> BTreeSet<String> {
+        let mut res = BTreeSet::new();
+        self.walk_sub_targets(|_, collection| {
+            res.extend(
+                collection
+                    .providers
+                    .keys()
+                    .filter_map(|id| id.path.as_ref().map(|p| p.to_string())),
+            );
+        });
+        res
+    }
+
+    /// A hash of the outputs of this collection: the default outputs of `DefaultInfo` and,
+    /// recursively, those of its sub-targets. Unlike a hash of the whole collection, it doesn't
+    /// change when only other providers do. The value is only meaningful within one process.
+    pub fn output_signature(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.walk_sub_targets(|path, collection| {
+            path.hash(&mut hasher);
+            let default_outputs = collection.default_info().default_outputs();
+            default_outputs.len().hash(&mut hasher);
+            for output in default_outputs {
+                output.artifact().hash(&mut hasher);
+            }
+        });
+        hasher.finish()
+    }
+
+    /// An indented tree of the providers in this collection, with the sub-targets of
+    /// `DefaultInfo` nested under it, for `buck2 audit providers --tree`. Unlike `Display`, only
+    /// the names of providers and sub-targets are shown. Sub-targets nested more than
+    /// `MAX_RENDER_TREE_DEPTH` deep are elided as `...`.
+    pub fn render_tree(&self) -> String {
+        const MAX_RENDER_TREE_DEPTH: usize = 16;
+
+        type Collections<'a> =
+            std::iter::Peekable<std::vec::IntoIter<(Vec<&'a str>, &'a FrozenProviderCollection)>>;
+
+        fn render(collections: &mut Collections<'_>, out: &mut String) {
+            use std::fmt::Write;
+
+            let (path, collection) = collections.next().unwrap();
+            let depth = path.len();
+            if depth > MAX_RENDER_TREE_DEPTH {
+                writeln!(out, "{:indent$}...", "", indent = depth * 4).unwrap();
+                return;
+            }
+            for id in collection.providers.keys() {
+                writeln!(out, "{:indent$}{}", "", id.name, indent = depth * 4).unwrap();
+                if *id != DefaultInfoCallable::provider_id_t().interned() {
+                    continue;
+                }
+                for name in collection.default_info().sub_targets().keys() {
+                    writeln!(out, "{:indent$}[{}]", "", name, indent = depth * 4 + 2).unwrap();
+                    // Sub-targets come right after their parent collection, unless skipped
+                    // by `walk_sub_targets` as a cycle.
+                    let next_is_sub_target = matches!(
+                        collections.peek(),
+                        Some((sub_path, _))
+                            if sub_path.len() == depth + 1 && sub_path.last() == Some(name)
+                    );
+                    if next_is_sub_target {
+                        render(collections, out);
+                    } else {
+                        writeln!(out, "{:indent$}<cycle>", "", indent = (depth + 1) * 4).unwrap();
+                    }
+                }
+            }
         }
 
-        fn get_default_info_default_outputs<'v>(value: Value<'v>) -> anyhow::Result<Value<'v>> {
-            let frozen = value
-                .unpack_frozen()
-                .expect("a frozen value to fetch DefaultInfo");
-            let collection = frozen
-                .downcast_ref::<FrozenProviderCollection>()
-                .ok_or_else(|| anyhow::anyhow!("{:?} was not a FrozenProviderCollection", value))?;
+        let mut collections = Vec::new();
+        self.walk_sub_targets(|path, collection| {
+            if path.len() <= MAX_RENDER_TREE_DEPTH + 1 {
+                collections.push((path.to_vec(), collection));
+            }
+        });
+        let mut out = String::new();
+        render(&mut collections.into_iter().peekable(), &mut out);
+        out
+    }
 
-            let ret = collection.default_info().default_outputs_raw().to_value();
-            Ok(ret)
+    /// Which providers are present in only one of `self` and `other`.
+    pub fn diff<'a>(&'a self, other: &'a FrozenProviderCollection) -> ProviderCollectionDiff<'a> {
+        ProviderCollectionDiff {
+            added: other
+                .providers
+                .keys()
+                .filter(|k| !self.providers.contains_key(*k))
+                .map(|k| &**k)
+                .collect(),
+            removed: self
+                .providers
+                .keys()
+                .filter(|k| !other.providers.contains_key(*k))
+                .map(|k| &**k)
+                .collect(),
         }
+    }
 
-        fn get_default_info_sub_targets<'v>(value: Value<'v>) -> anyhow::Result<Value<'v>> {
-            let frozen = value
-                .unpack_frozen()
-                .expect("a frozen value to fetch DefaultInfo");
-            let collection = frozen
-                .downcast_ref::<FrozenProviderCollection>()
-                .ok_or_else(|| anyhow::anyhow!("{:?} was not a FrozenProviderCollection", value))?;
+    /// The providers present in both `self` and `other` whose values serialize differently, in
+    /// the order of `self`. Unlike `diff`, this looks at values, and since the serialized form
+    /// doesn't depend on the heap it can compare a cached collection against a fresh one.
+    pub fn value_diff<'a>(
+        &'a self,
+        other: &FrozenProviderCollection,
+    ) -> anyhow::Result<Vec<&'a ProviderId>> {
+        let mut changed = Vec::new();
+        for (id, value) in &self.providers {
+            if let Some(other_value) = other.providers.get(id) {
+                if !serializes_equal(value, other_value)? {
+                    changed.push(&**id);
+                }
+            }
+        }
+        Ok(changed)
+    }
 
-            let ret = collection.default_info().sub_targets_raw().to_value();
-            Ok(ret)
+    /// A compact encoding of this collection suitable for use as a cache key: providers sorted by
+    /// their fully qualified id, each followed by its serialized value. Collections with the
+    /// same providers produce the same bytes regardless of provider order.
+    pub fn to_canonical_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        let mut providers: Vec<(&ProviderId, &FrozenValue)> =
+            self.providers.iter().map(|(id, v)| (&**id, v)).collect();
+        providers.sort_by_key(|(id, _)| *id);
+        let providers: Vec<(String, &FrozenValue)> = providers
+            .into_iter()
+            .map(|(id, v)| (canonical_provider_id(id), v))
+            .collect();
+        Ok(serde_json::to_vec(&providers)?)
+    }
+
+    /// Compare this collection against a snapshot recorded earlier with `to_canonical_bytes`,
+    /// e.g. to check in CI that the providers of a target didn't change unexpectedly.
+    pub fn diff_against_snapshot(&self, snapshot: &[u8]) -> anyhow::Result<SnapshotDiff> {
+        let recorded: BTreeMap<String, serde_json::Value> =
+            serde_json::from_slice::<Vec<(String, serde_json::Value)>>(snapshot)
+                .context(ProviderCollectionError::MalformedSnapshot)?
+                .into_iter()
+                .collect();
+        let current = self.canonical_values()?;
+
+        let mut diff = SnapshotDiff::default();
+        for (id, value) in &current {
+            match recorded.get(id) {
+                None => diff.added.push(id.clone()),
+                Some(recorded_value) if recorded_value != value => diff.changed.push(id.clone()),
+                Some(_) => {}
+            }
         }
+        diff.removed = recorded
+            .keys()
+            .filter(|id| !current.contains_key(*id))
+            .cloned()
+            .collect();
+        Ok(diff)
+    }
 
-        fn contains_provider<'v>(
-            collection: Value<'v>,
-            provider: Value<'v>,
-        ) -> anyhow::Result<bool> {
-            let id = provider
-                .as_provider_callable()
-                .unwrap()
-                .id()
-                .unwrap()
-                .dupe();
+    /// A readable report of how `other` differs from this collection, for
+    /// `buck2 audit providers --diff`: the providers added and removed, the fields whose value
+    /// changed, and the sub-targets added (`+`), removed (`-`) or changed (`~`). Each section is
+    /// sorted, and left out when empty, so identical collections produce an empty report.
+    pub fn change_report(&self, other: &FrozenProviderCollection) -> String {
+        fn sorted_names(ids: Vec<&ProviderId>) -> Vec<String> {
+            let mut names: Vec<String> = ids.into_iter().map(|id| id.name.clone()).collect();
+            names.sort();
+            names
+        }
+
+        fn changed_fields(
+            a: &FrozenProviderCollection,
+            b: &FrozenProviderCollection,
+        ) -> Vec<String> {
+            let default_info_id = DefaultInfoCallable::provider_id_t().interned();
+            let mut res = Vec::new();
+            for (id, value) in &a.providers {
+                let (provider, other_provider) = match (
+                    value.as_provider(),
+                    b.providers.get(id).and_then(|v| v.as_provider()),
+                ) {
+                    (Some(provider), Some(other_provider)) => (provider, other_provider),
+                    _ => continue,
+                };
+                let other_fields: SmallMap<&str, Value> =
+                    other_provider.items().into_iter().collect();
+                for (field, field_value) in provider.items() {
+                    // Sub-targets have their own section.
+                    if *id == default_info_id && field == "sub_targets" {
+                        continue;
+                    }
+                    let (before, after) = (
+                        field_value.to_repr(),
+                        other_fields
+                            .get(field)
+                            .map(|v| v.to_repr())
+                            .unwrap_or_default(),
+                    );
+                    if before != after {
+                        res.push(format!("{}.{}: {} -> {}", id.name, field, before, after));
+                    }
+                }
+            }
+            res.sort();
+            res
+        }
+
+        fn sub_target_tree(
+            collection: &FrozenProviderCollection,
+        ) -> BTreeMap<Vec<&str>, &FrozenProviderCollection> {
+            let mut res = BTreeMap::new();
+            collection.walk_sub_targets(|path, sub_target| {
+                res.insert(path.to_vec(), sub_target);
+            });
+            res
+        }
+
+        fn changed_sub_targets(
+            ours: &BTreeMap<Vec<&str>, &FrozenProviderCollection>,
+            theirs: &BTreeMap<Vec<&str>, &FrozenProviderCollection>,
+        ) -> Vec<String> {
+            // A sub-target changed if anything in its own sub-target tree did.
+            let changed = |name: &str| {
+                ours.keys()
+                    .chain(theirs.keys())
+                    .filter(|path| path.first() == Some(&name))
+                    .any(|path| match (ours.get(path), theirs.get(path)) {
+                        (Some(a), Some(b)) => {
+                            let diff = a.diff(b);
+                            !diff.added.is_empty()
+                                || !diff.removed.is_empty()
+                                || !changed_fields(a, b).is_empty()
+                        }
+                        _ => true,
+                    })
+            };
+            let mut names: Vec<&str> = ours
+                .keys()
+                .chain(theirs.keys())
+                .filter_map(|path| path.first().copied())
+                .collect();
+            names.sort_unstable();
+            names.dedup();
+            names
+                .into_iter()
+                .filter_map(|name| {
+                    match (
+                        ours.contains_key(&[name][..]),
+                        theirs.contains_key(&[name][..]),
+                    ) {
+                        (false, _) => Some(format!("+ [{}]", name)),
+                        (_, false) => Some(format!("- [{}]", name)),
+                        _ if changed(name) => Some(format!("~ [{}]", name)),
+                        _ => None,
+                    }
+                })
+                .collect()
+        }
+
+        let diff = self.diff(other);
+        let sections = [
+            ("Added providers", sorted_names(diff.added)),
+            ("Removed providers", sorted_names(diff.removed)),
+            ("Changed fields", changed_fields(self, other)),
+            (
+                "Sub-targets",
+                changed_sub_targets(&sub_target_tree(self), &sub_target_tree(other)),
+            ),
+        ];
+
+        let mut res = String::new();
+        for (title, lines) in sections {
+            if lines.is_empty() {
+                continue;
+            }
+            res.push_str(title);
+            res.push_str(":\n");
+            for line in lines {
+                res.push_str("  ");
+                res.push_str(&line);
+                res.push('\n');
+            }
+        }
+        res
+    }
+
+    /// The serialized value of each provider, by fully qualified id.
+    fn canonical_values(&self) -> anyhow::Result<BTreeMap<String, serde_json::Value>> {
+        self.providers
+            .iter()
+            .map(|(id, v)| anyhow::Ok((canonical_provider_id(id), serde_json::to_value(v)?)))
+            .collect()
+    }
+
+    /// Encode this collection as the providers that differ from `base`, e.g. to store the
+    /// collections of one target across many configurations compactly. Read it back with
+    /// `apply_delta` on the same base.
+    pub fn serialize_delta(&self, base: &FrozenProviderCollection) -> anyhow::Result<DeltaEncoded> {
+        let current = self.canonical_values()?;
+        let base_values = base.canonical_values()?;
+        Ok(DeltaEncoded {
+            base_fingerprint: base.fingerprint()?,
+            removed: base_values
+                .keys()
+                .filter(|id| !current.contains_key(*id))
+                .cloned()
+                .collect(),
+            changed: current
+                .into_iter()
+                .filter(|(id, value)| base_values.get(id) != Some(value))
+                .collect(),
+        })
+    }
+
+    /// The data of the collection `delta` was produced from by `serialize_delta`, with this
+    /// collection as the base. It's an error for this not to be the base of `delta`.
+    pub fn apply_delta(
+        &self,
+        delta: &DeltaEncoded,
+    ) -> anyhow::Result<DeserializedProviderCollection> {
+        let fingerprint = self.fingerprint()?;
+        if fingerprint != delta.base_fingerprint {
+            return Err(ProviderCollectionError::DeltaBaseMismatch {
+                expected: delta.base_fingerprint.clone(),
+                actual: fingerprint,
+            }
+            .into());
+        }
+        let mut values = self.canonical_values()?;
+        for id in &delta.removed {
+            values.remove(id);
+        }
+        values.extend(delta.changed.iter().cloned());
+        Ok(DeserializedProviderCollection::from_entries(
+            values.into_iter().collect(),
+        ))
+    }
+
+    /// A hash of the canonical bytes of this collection, stable across daemon runs.
+    fn fingerprint(&self) -> anyhow::Result<String> {
+        Ok(blake3::hash(&self.to_canonical_bytes()?)
+            .to_hex()
+            .to_string())
+    }
+
+    /// How `new`, a fresh analysis of the same target, differs from this collection in a way that
+    /// matters to dependents, so the incremental engine can decide what to recompute. Values are
+    /// compared by their serialized form, which is stable across heaps and daemon runs.
+    pub fn change_kind(&self, new: &FrozenProviderCollection) -> anyhow::Result<ChangeKind> {
+        if self.providers.len() != new.providers.len() {
+            return Ok(ChangeKind::ProvidersChanged);
+        }
+        let default_info_id = DefaultInfoCallable::provider_id_t().interned();
+        for (id, value) in &self.providers {
+            if *id == default_info_id {
+                continue;
+            }
+            match new.providers.get(id) {
+                Some(new_value) if serializes_equal(value, new_value)? => {}
+                _ => return Ok(ChangeKind::ProvidersChanged),
+            }
+        }
+        if self.output_signature() != new.output_signature()
+            || !serializes_equal(&self.default_info_value(), &new.default_info_value())?
+        {
+            return Ok(ChangeKind::OutputsChanged);
+        }
+        Ok(ChangeKind::Unchanged)
+    }
+
+    /// A short description of this collection for log lines, like
+    /// `Providers{BarInfo, DefaultInfo, FooInfo}`: sorted provider names only, with the names
+    /// past the first few elided.
+    pub fn one_line(&self) -> String {
+        let mut names: Vec<&str> = self.providers.keys().map(|k| k.name.as_str()).collect();
+        names.sort_unstable();
+        let shown = names.len().min(MAX_PROVIDERS_IN_ONE_LINE);
+        let mut res = format!("Providers{{{}", names[..shown].join(", "));
+        if shown < names.len() {
+            res.push_str(&format!(", +{} more", names.len() - shown));
+        }
+        res.push('}');
+        res
+    }
+
+    /// The declared field names of all the providers in this collection, e.g. to document the
+    /// fields a rule exposes.
+    pub fn all_field_names(&self) -> BTreeSet<String> {
+        self.providers
+            .values()
+            .filter_map(|v| v.as_provider())
+            .flat_map(|p| p.items().into_iter().map(|(name, _)| name.to_owned()))
+            .collect()
+    }
+
+    /// The name and Starlark type of each provider, sorted by name so the result doesn't depend
+    /// on provider order. A lighter description of the collection than `summary`.
+    pub fn provider_type_summary(&self) -> Vec<(String, String)> {
+        let mut res: Vec<(String, String)> = self
+            .providers
+            .iter()
+            .map(|(id, v)| (id.name.clone(), v.to_value().get_type().to_owned()))
+            .collect();
+        res.sort();
+        res
+    }
+
+    /// The estimated size in bytes of each provider, in provider order, e.g. to build a memory
+    /// flamegraph by provider type. This counts the provider and the data it owns, but not the
+    /// values its fields refer to, which live on the frozen heap and may be shared.
+    pub fn memory_by_provider(&self) -> Vec<(String, usize)> {
+        self.providers
+            .iter()
+            .map(|(id, v)| {
+                let size = match v.as_provider() {
+                    Some(provider) => {
+                        mem::size_of_val(provider)
+                            + allocative::size_of_unique_allocated_data(&ProviderAllocative(
+                                provider,
+                            ))
+                    }
+                    None => 0,
+                };
+                (id.name.clone(), size)
+            })
+            .collect()
+    }
+
+    /// Render the collection into a [`CollectionSummary`] that does not reference the frozen heap.
+    pub fn summary(&self) -> CollectionSummary {
+        CollectionSummary {
+            providers: self
+                .providers
+                .iter()
+                .map(|(id, v)| ProviderSummary {
+                    name: id.name.clone(),
+                    fields: v
+                        .as_provider()
+                        .map(|p| {
+                            p.items()
+                                .into_iter()
+                                .map(|(k, v)| (k.to_owned(), v.to_repr()))
+                                .collect()
+                        })
+                        .unwrap_or_default(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Lets a provider be passed where `allocative` expects a `&dyn Allocative`.
+struct ProviderAllocative<'a, 'v>(&'a dyn ProviderLike<'v>);
+
+impl<'a, 'v> Allocative for ProviderAllocative<'a, 'v> {
+    fn visit<'x, 'y: 'x>(&self, visitor: &'x mut Visitor<'y>) {
+        self.0.visit(visitor)
+    }
+}
+
+/// Providers produced by an intermediate analysis step, which unlike a `FrozenProviderCollection`
+/// doesn't have a `DefaultInfo` yet. `finalize` adds it to get a regular collection.
+#[derive(Debug, Default)]
+pub struct PartialProviderCollection {
+    providers: SmallMap<InternedProviderId, OwnedFrozenValue>,
+}
+
+impl PartialProviderCollection {
+    pub fn new() -> PartialProviderCollection {
+        PartialProviderCollection::default()
+    }
+
+    /// Add a provider. It's an error to add a provider that is already present, or `DefaultInfo`.
+    pub fn add(&mut self, value: OwnedFrozenValue) -> anyhow::Result<()> {
+        let provider = match value.value().as_provider() {
+            Some(provider) => provider,
+            None => {
+                return Err(ProviderCollectionError::CollectionElementNotAProvider {
+                    repr: value.value().to_repr(),
+                }
+                .into());
+            }
+        };
+        if provider.id() == DefaultInfoCallable::provider_id() {
+            return Err(ProviderCollectionError::DefaultInfoInPartialCollection.into());
+        }
+        let id = InternedProviderId::new(provider.id());
+        if let Some(existing_value) = self.providers.get(&id) {
+            return Err(ProviderCollectionError::CollectionSpecifiedProviderTwice {
+                provider_name: id.name.clone(),
+                original_repr: existing_value.value().to_repr(),
+                new_repr: value.value().to_repr(),
+            }
+            .into());
+        }
+        self.providers.insert(id, value);
+        Ok(())
+    }
+
+    pub fn contains_provider(&self, provider_id: &ProviderId) -> bool {
+        match InternedProviderId::get(provider_id) {
+            Some(id) => self.providers.contains_key(&id),
+            None => false,
+        }
+    }
+
+    /// Turn this into a regular collection, with `default_info` first.
+    pub fn finalize(
+        self,
+        default_info: OwnedFrozenValue,
+    ) -> anyhow::Result<FrozenProviderCollectionValue> {
+        let heap = FrozenHeap::new();
+        let default_info = heap.alloc(default_info);
+        check_frozen_default_info(default_info)?;
+        let mut providers = SmallMap::with_capacity(self.providers.len() + 1);
+        providers.insert(
+            DefaultInfoCallable::provider_id_t().interned(),
+            default_info,
+        );
+        for (id, value) in self.providers {
+            providers.insert(id, heap.alloc(value));
+        }
+        Ok(FrozenProviderCollectionValue::alloc_on(
+            heap,
+            FrozenProviderCollection {
+                providers,
+                default_info_synthesized: false,
+            },
+        ))
+    }
+}
+
+/// Serializes only the `DefaultInfo` of a collection: its default outputs and the names of its
+/// sub-targets. This is all that showing build outputs needs, and it avoids serializing user
+/// providers, which can be large and are opaque outside of Starlark.
+pub struct DefaultInfoOnly<'a>(pub &'a FrozenProviderCollection);
+
+impl<'a> Serialize for DefaultInfoOnly<'a> {
+    fn serialize<S>(&self, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let default_info = self.0.default_info();
+        let default_outputs = default_info.default_outputs();
+        let sub_targets = default_info.sub_targets();
+        let mut s = s.serialize_struct("DefaultInfo", 2)?;
+        s.serialize_field(
+            "default_outputs",
+            &default_outputs.iter().map(|a| &**a).collect::<Vec<_>>(),
+        )?;
+        s.serialize_field("sub_targets", &sub_targets.keys().collect::<Vec<_>>())?;
+        s.end()
+    }
+}
+
+/// A view of a collection for human-facing output such as audit commands, as returned by
+/// `FrozenProviderCollection::simplify`.
+///
+/// A sub-target is shown as its output when its collection is nothing but a `DefaultInfo` with
+/// exactly one default output, no other outputs and no sub-targets. Every other sub-target is
+/// shown as a collection, simplified the same way. Nothing is copied or dropped: lookups should
+/// go through `collection`, which still has every provider.
+#[derive(Debug, Clone, Copy)]
+pub struct SimplifiedProviderCollection<'a> {
+    collection: &'a FrozenProviderCollection,
+}
+
+/// A sub-target in a `SimplifiedProviderCollection`.
+#[derive(Debug, Clone, Copy)]
+pub enum SimplifiedSubTarget {
+    Output(FrozenRef<'static, StarlarkArtifact>),
+    Collection(SimplifiedProviderCollection<'static>),
+}
+
+impl<'a> SimplifiedProviderCollection<'a> {
+    pub fn collection(&self) -> &'a FrozenProviderCollection {
+        self.collection
+    }
+
+    pub fn sub_targets(&self) -> SmallMap<&'static str, SimplifiedSubTarget> {
+        self.collection
+            .default_info()
+            .as_ref()
+            .sub_targets()
+            .into_iter()
+            .map(|(name, sub_target)| {
+                let sub_target = match sub_target.single_output() {
+                    Some(output) => SimplifiedSubTarget::Output(output),
+                    None => SimplifiedSubTarget::Collection(sub_target.as_ref().simplify()),
+                };
+                (name, sub_target)
+            })
+            .collect()
+    }
+}
+
+impl<'a> Serialize for SimplifiedProviderCollection<'a> {
+    fn serialize<S>(&self, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut s = s.serialize_struct("SimplifiedProviderCollection", 2)?;
+        s.serialize_field("providers", &self.collection.provider_names())?;
+        s.serialize_field("sub_targets", &SerializeSubTargets(self.sub_targets()))?;
+        s.end()
+    }
+}
+
+struct SerializeSubTargets(SmallMap<&'static str, SimplifiedSubTarget>);
+
+impl Serialize for SerializeSubTargets {
+    fn serialize<S>(&self, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        s.collect_map(self.0.iter())
+    }
+}
+
+impl Serialize for SimplifiedSubTarget {
+    fn serialize<S>(&self, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self {
+            SimplifiedSubTarget::Output(output) => output.serialize(s),
+            SimplifiedSubTarget::Collection(collection) => collection.serialize(s),
+        }
+    }
+}
+
+/// Look up the same provider in each of `collections`, returning the results in the same order.
+pub fn collect_provider_across<T: StarlarkValue<'static>>(
+    collections: &[&FrozenProviderCollection],
+    provider_id: &ProviderIdWithType<T>,
+) -> Vec<Option<FrozenRef<'static, T>>> {
+    collections
+        .iter()
+        .map(|collection| collection.get_provider(provider_id))
+        .collect()
+}
+
+/// The fully qualified id of a provider, as used in canonical bytes and snapshots.
+fn canonical_provider_id(id: &ProviderId) -> String {
+    match &id.path {
+        Some(path) => format!("{}:{}", path, id.name),
+        None => id.name.clone(),
+    }
+}
+
+/// Receives every lookup made through a `TracingProviderCollection`: the operation, the provider
+/// requested, and whether the collection has it.
+pub type ProviderAccessSink = Box<dyn Fn(GetOp, &ProviderId, bool) + Send + Sync + 'static>;
+
+/// Wraps a collection to report every lookup made through it to a sink, to debug how the
+/// providers of a single target of interest are used.
+pub struct TracingProviderCollection<'a> {
+    collection: &'a FrozenProviderCollection,
+    sink: ProviderAccessSink,
+}
+
+impl<'a> TracingProviderCollection<'a> {
+    pub fn new(
+        collection: &'a FrozenProviderCollection,
+        sink: ProviderAccessSink,
+    ) -> TracingProviderCollection<'a> {
+        TracingProviderCollection { collection, sink }
+    }
+
+    /// The wrapped collection. Lookups made on it directly are not reported.
+    pub fn collection(&self) -> &'a FrozenProviderCollection {
+        self.collection
+    }
+
+    pub fn get_provider_raw(&self, provider_id: &ProviderId) -> Option<&'a FrozenValue> {
+        let res = self.collection.get_provider_raw(provider_id);
+        (self.sink)(GetOp::Get, provider_id, res.is_some());
+        res
+    }
+
+    pub fn contains_provider(&self, provider_id: &ProviderId) -> bool {
+        let res = self.collection.contains_provider(provider_id);
+        (self.sink)(GetOp::In, provider_id, res);
+        res
+    }
+
+    /// Like `get_provider_raw`, but it's an error for the provider to be missing.
+    pub fn at(&self, provider_id: &ProviderId) -> anyhow::Result<&'a FrozenValue> {
+        let res = self.collection.get_provider_raw(provider_id);
+        (self.sink)(GetOp::At, provider_id, res.is_some());
+        res.ok_or_else(|| {
+            ProviderCollectionError::AtNotFound(
+                provider_id.name.clone(),
+                self.collection.provider_names(),
+            )
+            .into()
+        })
+    }
+}
+
+/// Whether two provider values have the same serialized form.
+fn serializes_equal(a: &FrozenValue, b: &FrozenValue) -> anyhow::Result<bool> {
+    Ok(serde_json::to_value(a)? == serde_json::to_value(b)?)
+}
+
+/// How the providers of a target changed between two analyses, as returned by
+/// `FrozenProviderCollection::change_kind`. Ordered from least to most invalidating.
+#[derive(Debug, Clone, Copy, Dupe, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ChangeKind {
+    /// Nothing changed, dependents can be reused as is.
+    Unchanged,
+    /// Only `DefaultInfo` changed, so dependents that only consume its outputs need to rebuild
+    /// but not to be reanalyzed.
+    OutputsChanged,
+    /// Other providers were added, removed or changed, so dependents need to be reanalyzed.
+    ProvidersChanged,
+}
+
+/// The data of a provider collection read back from the output of
+/// `FrozenProviderCollection::to_canonical_bytes`, e.g. to replay a recorded collection in a
+/// golden test. The providers are plain data rather than Starlark values, so they can't be used
+/// in analysis.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeserializedProviderCollection {
+    providers: Vec<DeserializedProvider>,
+}
+
+/// A single provider in a [`DeserializedProviderCollection`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeserializedProvider {
+    /// The fully qualified id of the provider, like `root//defs.bzl:FooInfo`.
+    pub id: String,
+    pub name: String,
+    pub value: serde_json::Value,
+}
+
+impl DeserializedProviderCollection {
+    pub fn from_json(bytes: &[u8]) -> anyhow::Result<Self> {
+        Ok(Self::from_entries(
+            serde_json::from_slice::<Vec<(String, serde_json::Value)>>(bytes)
+                .context(ProviderCollectionError::MalformedSnapshot)?,
+        ))
+    }
+
+    fn from_entries(entries: Vec<(String, serde_json::Value)>) -> Self {
+        let mut providers: Vec<DeserializedProvider> = entries
+            .into_iter()
+            .map(|(id, value)| DeserializedProvider {
+                name: match id.rsplit_once(':') {
+                    Some((_, name)) => name.to_owned(),
+                    None => id.clone(),
+                },
+                id,
+                value,
+            })
+            .collect();
+        providers.sort_by(|a, b| a.id.cmp(&b.id));
+        DeserializedProviderCollection { providers }
+    }
+
+    /// The providers, sorted by their fully qualified id.
+    pub fn providers(&self) -> &[DeserializedProvider] {
+        &self.providers
+    }
+
+    pub fn provider_names(&self) -> Vec<&str> {
+        self.providers.iter().map(|p| p.name.as_str()).collect()
+    }
+
+    /// The value of the provider called `name`. Providers from different files with the same
+    /// name can't be told apart this way, look them up in `providers` instead.
+    pub fn get(&self, name: &str) -> Option<&serde_json::Value> {
+        self.providers
+            .iter()
+            .find(|p| p.name == name)
+            .map(|p| &p.value)
+    }
+}
+
+/// A provider collection encoded as its differences from a base collection, as returned by
+/// `FrozenProviderCollection::serialize_delta`. Providers are listed by their fully qualified
+/// id, sorted.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeltaEncoded {
+    /// Identifies the base collection the delta applies to.
+    pub base_fingerprint: String,
+    /// Providers added or changed relative to the base, with their serialized value.
+    pub changed: Vec<(String, serde_json::Value)>,
+    /// Providers of the base which are not in the collection.
+    pub removed: Vec<String>,
+}
+
+/// How a collection differs from a snapshot, as returned by
+/// `FrozenProviderCollection::diff_against_snapshot`. Providers are listed by their fully
+/// qualified id, sorted.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SnapshotDiff {
+    /// Providers present in the collection but not the snapshot.
+    pub added: Vec<String>,
+    /// Providers present in the snapshot but not the collection.
+    pub removed: Vec<String>,
+    /// Providers present in both, with a different value.
+    pub changed: Vec<String>,
+}
+
+impl SnapshotDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// The provider ids that differ between two collections, as returned by
+/// `FrozenProviderCollection::diff`. Ids are listed in collection order.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ProviderCollectionDiff<'a> {
+    /// Providers present in the other collection but not this one.
+    pub added: Vec<&'a ProviderId>,
+    /// Providers present in this collection but not the other one.
+    pub removed: Vec<&'a ProviderId>,
+}
+
+/// Owned, heap-free description of a `FrozenProviderCollection`, used to pass the contents of a
+/// collection to other threads (e.g. for logging) without keeping the frozen heap alive.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CollectionSummary {
+    pub providers: Vec<ProviderSummary>,
+}
+
+/// A single provider in a [`CollectionSummary`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ProviderSummary {
+    pub name: String,
+    /// Field names and the `repr` of their values, in declaration order.
+    pub fields: Vec<(String, String)>,
+}
+
+/// Thin wrapper around `FrozenValue` that can only be constructed if that value is a `FrozenProviderCollection`
+#[derive(Debug, Clone, Dupe, Allocative)]
+pub struct FrozenProviderCollectionValue {
+    #[allocative(skip)] // TODO(nga): do not skip.
+    value: OwnedFrozenValueTyped<FrozenProviderCollection>,
+}
+
+impl Serialize for FrozenProviderCollectionValue {
+    fn serialize<S>(&self, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        (*self.value).serialize(s)
+    }
+}
+
+impl FrozenProviderCollectionValue {
+    pub fn from_value(value: OwnedFrozenValueTyped<FrozenProviderCollection>) -> Self {
+        Self { value }
+    }
+
+    pub fn try_from_value(value: OwnedFrozenValue) -> anyhow::Result<Self> {
+        Ok(Self {
+            value: value.downcast().map_err(|value| {
+                anyhow::anyhow!("{:?} was not a FrozenProviderCollection", value)
+            })?,
+        })
+    }
+
+    pub fn value(&self) -> &OwnedFrozenValueTyped<FrozenProviderCollection> {
+        &self.value
+    }
+
+    pub fn provider_collection(&self) -> &FrozenProviderCollection {
+        self.value.as_ref()
+    }
+
+    /// Allocate `collection` on `heap`, which must keep alive every value in `collection`.
+    fn alloc_on(heap: FrozenHeap, collection: FrozenProviderCollection) -> Self {
+        let value = heap.alloc(collection);
+        // SAFETY: `heap` keeps `value` alive, and with it the values it points to.
+        Self::try_from_value(unsafe { OwnedFrozenValue::new(heap.into_ref(), value) })
+            .expect("just allocated a provider collection")
+    }
+
+    /// `value`, a value of this collection, along with the heap keeping it alive.
+    fn owned(&self, value: FrozenValue) -> OwnedFrozenValue {
+        self.value.to_owned_frozen_value().map(|_| value)
+    }
+
+    /// A copy of this collection containing only its `DefaultInfo`, for callers that only care
+    /// about the default outputs.
+    pub fn default_info_only(&self) -> FrozenProviderCollectionValue {
+        let heap = FrozenHeap::new();
+        heap.add_reference(self.value.owner());
+        Self::alloc_on(heap, self.provider_collection().filtered(|_, _| false))
+    }
+
+    /// A copy of this collection with providers moved to new ids according to `table`, for bulk
+    /// provider renames during a migration. Providers not in `table` keep their id. Remapping
+    /// from or to `DefaultInfo` is an error, as is two providers ending up with the same id.
+    pub fn remap_ids(
+        &self,
+        table: &HashMap<Arc<ProviderId>, Arc<ProviderId>>,
+    ) -> anyhow::Result<FrozenProviderCollectionValue> {
+        let default_info = DefaultInfoCallable::provider_id();
+        if table.contains_key(default_info) || table.values().any(|to| to == default_info) {
+            return Err(ProviderCollectionError::CannotRemapDefaultInfo.into());
+        }
+
+        let collection = self.provider_collection();
+        let mut providers = SmallMap::with_capacity(collection.providers.len());
+        for (id, v) in &collection.providers {
+            let new_id = match table.get(&**id) {
+                Some(to) => InternedProviderId::new(to),
+                None => *id,
+            };
+            if providers.insert(new_id, *v).is_some() {
+                return Err(ProviderCollectionError::RemapCollision(new_id.name.clone()).into());
+            }
+        }
+        let heap = FrozenHeap::new();
+        heap.add_reference(self.value.owner());
+        Ok(Self::alloc_on(
+            heap,
+            FrozenProviderCollection {
+                providers,
+                default_info_synthesized: collection.default_info_synthesized,
+            },
+        ))
+    }
+
+    /// A collection with the providers of `other` followed by `plus`, for a target re-exporting
+    /// a dependency's providers. The providers of `other` are shared rather than copied. It's an
+    /// error for `plus` to contain a provider `other` already has, including `DefaultInfo`.
+    pub fn sharing_from(
+        other: &FrozenProviderCollectionValue,
+        plus: &[OwnedFrozenValue],
+    ) -> anyhow::Result<FrozenProviderCollectionValue> {
+        let heap = FrozenHeap::new();
+        heap.add_reference(other.value.owner());
+        let mut providers = other.provider_collection().providers.clone();
+        for value in plus {
+            let value = heap.alloc(value.dupe());
+            let provider = match value.as_provider() {
+                Some(provider) => provider,
+                None => {
+                    return Err(ProviderCollectionError::CollectionElementNotAProvider {
+                        repr: value.to_value().to_repr(),
+                    }
+                    .into());
+                }
+            };
+            if let Some(existing_value) =
+                providers.insert(InternedProviderId::new(provider.id()), value)
+            {
+                return Err(ProviderCollectionError::CollectionSpecifiedProviderTwice {
+                    provider_name: provider.id().name.clone(),
+                    original_repr: existing_value.to_value().to_repr(),
+                    new_repr: value.to_value().to_repr(),
+                }
+                .into());
+            }
+        }
+        Ok(Self::alloc_on(
+            heap,
+            FrozenProviderCollection {
+                providers,
+                default_info_synthesized: other.provider_collection().default_info_synthesized,
+            },
+        ))
+    }
+
+    /// A copy of this collection with `DefaultInfo` replaced by the result of `f`, e.g. to
+    /// rewrite its outputs. Other providers are shared unchanged. It's an error for `f` to return
+    /// anything but a `DefaultInfo`.
+    pub fn map_default_info(
+        &self,
+        f: impl Fn(FrozenRef<FrozenDefaultInfo>) -> anyhow::Result<OwnedFrozenValue>,
+    ) -> anyhow::Result<FrozenProviderCollectionValue> {
+        let collection = self.provider_collection();
+        let heap = FrozenHeap::new();
+        heap.add_reference(self.value.owner());
+        let default_info = heap.alloc(f(collection.default_info())?);
+        check_frozen_default_info(default_info)?;
+        let mut providers = collection.providers.clone();
+        providers.insert(
+            DefaultInfoCallable::provider_id_t().interned(),
+            default_info,
+        );
+        Ok(Self::alloc_on(
+            heap,
+            FrozenProviderCollection {
+                providers,
+                default_info_synthesized: collection.default_info_synthesized,
+            },
+        ))
+    }
+
+    /// Merge `self` and `other`. Providers present in only one of them are taken as is, and
+    /// `resolver` is called with the values from `self` and `other` for each provider present in
+    /// both, including `DefaultInfo`, to produce the merged value. Providers keep the order of
+    /// `self`, followed by those only in `other`.
+    pub fn merge_with(
+        &self,
+        other: &FrozenProviderCollectionValue,
+        resolver: impl Fn(
+            &ProviderId,
+            OwnedFrozenValue,
+            OwnedFrozenValue,
+        ) -> anyhow::Result<OwnedFrozenValue>,
+    ) -> anyhow::Result<FrozenProviderCollectionValue> {
+        let (collection, other_collection) =
+            (self.provider_collection(), other.provider_collection());
+        let heap = FrozenHeap::new();
+        heap.add_reference(self.value.owner());
+        heap.add_reference(other.value.owner());
+        let mut providers =
+            SmallMap::with_capacity(collection.providers.len() + other_collection.providers.len());
+        for (id, value) in &collection.providers {
+            let value = match other_collection.providers.get(id) {
+                Some(other_value) => {
+                    let merged = heap.alloc(resolver(
+                        &**id,
+                        self.owned(*value),
+                        other.owned(*other_value),
+                    )?);
+                    if merged
+                        .to_value()
+                        .as_provider()
+                        .map(|p| InternedProviderId::new(p.id()))
+                        != Some(*id)
+                    {
+                        return Err(ProviderCollectionError::MergeResolvedToWrongProvider {
+                            provider_name: id.name.clone(),
+                            repr: merged.to_value().to_repr(),
+                        }
+                        .into());
+                    }
+                    merged
+                }
+                None => *value,
+            };
+            providers.insert(*id, value);
+        }
+        for (id, value) in &other_collection.providers {
+            if !collection.providers.contains_key(id) {
+                providers.insert(*id, *value);
+            }
+        }
+        Ok(Self::alloc_on(
+            heap,
+            FrozenProviderCollection {
+                providers,
+                default_info_synthesized: collection.default_info_synthesized
+                    && other_collection.default_info_synthesized,
+            },
+        ))
+    }
+
+    /// Every label reachable from `base` through `DefaultInfo` sub-targets, where `base` is the
+    /// label this collection was obtained for. `base` itself comes first, and each label is
+    /// followed by the labels of its own sub-targets.
+    pub fn reachable_labels(
+        &self,
+        base: &ConfiguredProvidersLabel,
+    ) -> anyhow::Result<Vec<ConfiguredProvidersLabel>> {
+        let mut paths = Vec::new();
+        self.provider_collection()
+            .walk_sub_targets(|path, _| paths.push(path.to_vec()));
+        paths
+            .into_iter()
+            .map(|path| {
+                let mut name = base.name().clone();
+                for sub_name in path {
+                    name = name.push(ProviderName::new(sub_name.to_owned())?);
+                }
+                Ok(ConfiguredProvidersLabel::new(base.target().dupe(), name))
+            })
+            .collect()
+    }
+
+    pub fn lookup_inner(&self, label: &ConfiguredProvidersLabel) -> anyhow::Result<Self> {
+        match label.name() {
+            ProvidersName::Default => anyhow::Ok(self.dupe()),
+            ProvidersName::NonDefault(box NonDefaultProvidersName::Named(provider_names)) => {
+                Ok(FrozenProviderCollectionValue::from_value(
+                    self.value().try_map(|v| {
+                        let mut collection_value = v;
+
+                        for provider_name in &**provider_names {
+                            let maybe_di = collection_value
+                                .default_info()
+                                .get_sub_target_providers(provider_name.as_str());
+
+                            match maybe_di {
+                                // The inner values should all be frozen if in a frozen provider collection
+                                Some(inner) => {
+                                    collection_value = inner;
+                                }
+                                None => {
+                                    return Err(anyhow::anyhow!(
+                                        ProviderCollectionError::RequestedInvalidSubTarget(
+                                            provider_name.clone(),
+                                            label.clone(),
+                                            v.default_info()
+                                                .sub_targets()
+                                                .keys()
+                                                .map(|s| (*s).to_owned())
+                                                .collect()
+                                        )
+                                    ));
+                                }
+                            }
+                        }
+
+                        Ok(collection_value)
+                    })?,
+                ))
+            }
+            ProvidersName::NonDefault(box NonDefaultProvidersName::UnrecognizedFlavor(flavor)) => {
+                *REJECTED_FLAVORS
+                    .lock()
+                    .unwrap()
+                    .entry((**flavor).to_owned())
+                    .or_default() += 1;
+                Err(ProviderCollectionError::UnknownFlavors {
+                    target: label.unconfigured().to_string(),
+                    flavor: (**flavor).to_owned(),
+                }
+                .into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tester {
+    use std::collections::BTreeMap;
+
+    use buck2_interpreter_for_build::provider::callable::ValueAsProviderCallableLike;
+    use dupe::Dupe;
+    use starlark::environment::GlobalsBuilder;
+    use starlark::values::dict::AllocDict;
+    use starlark::values::list::ListRef;
+    use starlark::values::none::NoneType;
+    use starlark::values::Value;
+    use starlark::values::ValueLike;
+
+    use crate::interpreter::rule_defs::artifact::StarlarkArtifactLike;
+    use crate::interpreter::rule_defs::artifact::ValueAsArtifactLike;
+    use crate::interpreter::rule_defs::provider::collection::DefaultInfoOnly;
+    use crate::interpreter::rule_defs::provider::collection::FrozenProviderCollection;
+    use crate::interpreter::rule_defs::provider::collection::SimplifiedSubTarget;
+    use crate::interpreter::rule_defs::provider::deprecation::register_deprecated_provider;
+    use crate::interpreter::rule_defs::provider::deprecation::ProviderDeprecation;
+    use crate::interpreter::rule_defs::provider::ProviderCollection;
+    use crate::interpreter::rule_defs::provider::ValueAsProviderLike;
+
+    fn unpack_collection<'v>(value: Value<'v>) -> anyhow::Result<&'v FrozenProviderCollection> {
+        value
+            .unpack_frozen()
+            .and_then(|frozen| frozen.downcast_ref::<FrozenProviderCollection>())
+            .ok_or_else(|| anyhow::anyhow!("{:?} was not a FrozenProviderCollection", value))
+    }
+
+    #[starlark_module]
+    pub fn collection_creator(builder: &mut GlobalsBuilder) {
+        fn create_collection<'v>(value: Value<'v>) -> anyhow::Result<ProviderCollection<'v>> {
+            ProviderCollection::try_from_value(value)
+        }
+
+        fn get_default_info_default_outputs<'v>(value: Value<'v>) -> anyhow::Result<Value<'v>> {
+            let collection = unpack_collection(value)?;
+
+            let ret = collection.default_info().default_outputs_raw().to_value();
+            Ok(ret)
+        }
+
+        fn get_default_info_sub_targets<'v>(value: Value<'v>) -> anyhow::Result<Value<'v>> {
+            let collection = unpack_collection(value)?;
+
+            let ret = collection.default_info().sub_targets_raw().to_value();
+            Ok(ret)
+        }
+
+        fn contains_provider<'v>(
+            collection: Value<'v>,
+            provider: Value<'v>,
+        ) -> anyhow::Result<bool> {
+            let id = provider
+                .as_provider_callable()
+                .unwrap()
+                .id()
+                .unwrap()
+                .dupe();
+
+            let res = unpack_collection(collection)?.contains_provider(&id);
+
+            Ok(res)
+        }
+
+        fn providers_symmetric_diff<'v>(
+            collection1: Value<'v>,
+            collection2: Value<'v>,
+        ) -> anyhow::Result<Vec<String>> {
+            let diff = unpack_collection(collection1)?.diff(unpack_collection(collection2)?);
+
+            let mut names: Vec<String> = diff
+                .added
+                .iter()
+                .chain(diff.removed.iter())
+                .map(|id| id.name.clone())
+                .collect();
+            names.sort();
+            Ok(names)
+        }
+
+        fn providers_by_file<'v>(
+            collection: Value<'v>,
+        ) -> anyhow::Result<AllocDict<BTreeMap<String, Vec<String>>>> {
+            Ok(AllocDict(
+                unpack_collection(collection)?.providers_by_file(),
+            ))
+        }
+
+        fn providers_from_file<'v>(
+            collection: Value<'v>,
+            path: &str,
+        ) -> anyhow::Result<Vec<String>> {
+            Ok(unpack_collection(collection)?
+                .providers_from_file(path)
+                .into_iter()
+                .map(|id| id.name.clone())
+                .collect())
+        }
+
+        fn all_defining_files_recursive<'v>(collection: Value<'v>) -> anyhow::Result<Vec<String>> {
+            Ok(unpack_collection(collection)?
+                .all_defining_files_recursive()
+                .into_iter()
+                .collect())
+        }
+
+        fn deprecate_provider<'v>(
+            provider: Value<'v>,
+            reason: Option<&str>,
+            replacement: Option<&str>,
+        ) -> anyhow::Result<NoneType> {
+            register_deprecated_provider(
+                &provider.as_provider_callable().unwrap().require_id()?,
+                ProviderDeprecation {
+                    reason: reason.map(|s| s.to_owned()),
+                    replacement: replacement.map(|s| s.to_owned()),
+                },
+            );
+            Ok(NoneType)
+        }
+
+        fn output_signature<'v>(collection: Value<'v>) -> anyhow::Result<String> {
+            Ok(unpack_collection(collection)?
+                .output_signature()
+                .to_string())
+        }
+
+        fn serialize_default_info_only<'v>(collection: Value<'v>) -> anyhow::Result<String> {
+            Ok(serde_json::to_string(&DefaultInfoOnly(unpack_collection(
+                collection,
+            )?))?)
+        }
+
+        fn simplified_sub_targets<'v>(
+            collection: Value<'v>,
+        ) -> anyhow::Result<AllocDict<BTreeMap<String, String>>> {
+            let collection = unpack_collection(collection)?;
+            Ok(AllocDict(
+                collection
+                    .simplify()
+                    .sub_targets()
+                    .into_iter()
+                    .map(|(name, sub_target)| {
+                        let kind = match sub_target {
+                            SimplifiedSubTarget::Output(_) => "output",
+                            SimplifiedSubTarget::Collection(_) => "collection",
+                        };
+                        (name.to_owned(), kind.to_owned())
+                    })
+                    .collect(),
+            ))
+        }
+
+        fn assert_outputs_unique<'v>(collection: Value<'v>) -> anyhow::Result<NoneType> {
+            unpack_collection(collection)?.assert_outputs_unique()?;
+            Ok(NoneType)
+        }
+
+        fn has_default_artifacts<'v>(
+            collection: Value<'v>,
+            expected: Vec<Value<'v>>,
+        ) -> anyhow::Result<bool> {
+            let expected = expected
+                .iter()
+                .map(|artifact| {
+                    artifact
+                        .as_artifact()
+                        .ok_or_else(|| anyhow::anyhow!("{} was not an artifact", artifact))?
+                        .get_bound_artifact()
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            Ok(expected == unpack_collection(collection)?.default_artifacts())
+        }
+
+        fn serialize_simplified<'v>(collection: Value<'v>) -> anyhow::Result<String> {
+            Ok(serde_json::to_string(
+                &unpack_collection(collection)?.simplify(),
+            )?)
+        }
+
+        /// Fails unless `unfrozen_collection` would be equal to the frozen `frozen_golden` once
+        /// frozen. Freezing it here would overwrite the caller's values with forwards, so this
+        /// compares, provider by provider and field by field, what freezing would copy.
+        fn assert_freezes_to<'v>(
+            unfrozen_collection: Value<'v>,
+            frozen_golden: Value<'v>,
+        ) -> anyhow::Result<NoneType> {
+            if unfrozen_collection.unpack_frozen().is_some() {
+                return Err(anyhow::anyhow!("{} is already frozen", unfrozen_collection));
+            }
+            let unfrozen =
+                ProviderCollection::from_value(unfrozen_collection).ok_or_else(|| {
+                    anyhow::anyhow!("{:?} was not a ProviderCollection", unfrozen_collection)
+                })?;
+            let golden = unpack_collection(frozen_golden)?;
+
+            let mismatch = || {
+                anyhow::anyhow!(
+                    "{} does not freeze to {}",
+                    unfrozen_collection,
+                    frozen_golden
+                )
+            };
+            if unfrozen.providers.len() != golden.providers.len() {
+                return Err(mismatch());
+            }
+            for (id, value) in &unfrozen.providers {
+                let golden_value = golden.providers.get(id).ok_or_else(mismatch)?;
+                let (fields, golden_fields) =
+                    match (value.as_provider(), golden_value.as_provider()) {
+                        (Some(provider), Some(golden_provider)) => {
+                            (provider.items(), golden_provider.items())
+                        }
+                        _ => return Err(mismatch()),
+                    };
+                if fields.len() != golden_fields.len() {
+                    return Err(mismatch());
+                }
+                for (name, field) in fields {
+                    match golden_fields
+                        .iter()
+                        .find(|(golden_name, _)| *golden_name == name)
+                    {
+                        Some((_, golden_field)) if field.equals(*golden_field)? => {}
+                        _ => return Err(mismatch()),
+                    }
+                }
+            }
+            Ok(NoneType)
+        }
+
+        fn providers_list<'v>(collection: Value<'v>) -> anyhow::Result<Vec<String>> {
+            Ok(unpack_collection(collection)?.provider_names())
+        }
+
+        /// Fails unless `collection` contains exactly the providers in the list `expected`, with
+        /// equal values, in any order, e.g. `[DefaultInfo(), FooInfo(foo = "x")]`. A
+        /// `DefaultInfo` synthesized by buck2 may be omitted from `expected`.
+        fn assert_providers_equal<'v>(
+            collection: Value<'v>,
+            expected: Value<'v>,
+        ) -> anyhow::Result<NoneType> {
+            let providers = ProviderCollection::from_value(collection)
+                .ok_or_else(|| anyhow::anyhow!("{:?} was not a ProviderCollection", collection))?;
+            let list = ListRef::from_value(expected)
+                .ok_or_else(|| anyhow::anyhow!("{} is not a list", expected))?;
+            if !providers.equals_provider_list(list)? {
+                return Err(anyhow::anyhow!(
+                    "{} does not have the providers {}",
+                    collection,
+                    expected
+                ));
+            }
+            Ok(NoneType)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Hooks, sinks and registries are process-wide and other tests run concurrently, so tests
+    // checking what was reported to them only look for what they reported themselves.
+
+    use std::cell::Cell;
+    use std::collections::HashMap;
+    use std::mem;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+    use std::sync::Once;
+
+    use buck2_common::result::SharedResult;
+    use buck2_core::bzl::ImportPath;
+    use buck2_core::configuration::data::ConfigurationData;
+    use buck2_core::provider::id::InternedProviderId;
+    use buck2_core::provider::id::ProviderId;
+    use buck2_core::provider::label::testing::ProvidersLabelTestExt;
+    use buck2_core::provider::label::ConfiguredProvidersLabel;
+    use buck2_core::provider::label::NonDefaultProvidersName;
+    use buck2_core::provider::label::ProvidersLabel;
+    use buck2_core::provider::label::ProvidersName;
+    use buck2_interpreter_for_build::attrs::coerce;
+    use buck2_interpreter_for_build::interpreter::testing::expect_error;
+    use buck2_interpreter_for_build::interpreter::testing::Tester;
+    use buck2_interpreter_for_build::provider::callable::ProviderCallableLike;
+    use buck2_interpreter_for_build::provider::callable::ValueAsProviderCallableLike;
+    use dupe::Dupe;
+    use indoc::indoc;
+    use once_cell::sync::Lazy;
+    use starlark::environment::Globals;
+    use starlark::environment::GlobalsBuilder;
+    use starlark::environment::Module;
+    use starlark::values::list::ListRef;
+    use starlark::values::FrozenValue;
+    use starlark::values::Heap;
+    use starlark::values::StarlarkValue;
+    use starlark::values::Value;
+
+    use crate::interpreter::build_defs::register_provider;
+    use crate::interpreter::rule_defs::artifact::testing::artifactory;
+    use crate::interpreter::rule_defs::provider::builtin::run_info::RunInfoCallable;
+    use crate::interpreter::rule_defs::provider::collection::canonical_provider_id;
+    use crate::interpreter::rule_defs::provider::collection::collect_provider_across;
+    use crate::interpreter::rule_defs::provider::collection::format_provider_keys_truncated;
+    use crate::interpreter::rule_defs::provider::collection::index_not_a_provider;
+    use crate::interpreter::rule_defs::provider::collection::init_construction_error_sink;
+    use crate::interpreter::rule_defs::provider::collection::rejected_flavors;
+    use crate::interpreter::rule_defs::provider::collection::set_collection_construction_observer;
+    use crate::interpreter::rule_defs::provider::collection::tester::collection_creator;
+    use crate::interpreter::rule_defs::provider::collection::ChangeKind;
+    use crate::interpreter::rule_defs::provider::collection::CollectedConstructionError;
+    use crate::interpreter::rule_defs::provider::collection::CollectionConstructionTiming;
+    use crate::interpreter::rule_defs::provider::collection::ConstructionErrorCollector;
+    use crate::interpreter::rule_defs::provider::collection::DeltaEncoded;
+    use crate::interpreter::rule_defs::provider::collection::DeserializedProviderCollection;
+    use crate::interpreter::rule_defs::provider::collection::FrozenProviderCollection;
+    use crate::interpreter::rule_defs::provider::collection::FrozenProviderCollectionValue;
+    use crate::interpreter::rule_defs::provider::collection::GetOp;
+    use crate::interpreter::rule_defs::provider::collection::LazyProviderCollection;
+    use crate::interpreter::rule_defs::provider::collection::PartialProviderCollection;
+    use crate::interpreter::rule_defs::provider::collection::SnapshotDiff;
+    use crate::interpreter::rule_defs::provider::collection::TracingProviderCollection;
+    use crate::interpreter::rule_defs::provider::factory::register_provider_factory;
+    use crate::interpreter::rule_defs::provider::factory::with_provider_factories_enabled;
+    use crate::interpreter::rule_defs::provider::internal::register_internal_provider;
+    use crate::interpreter::rule_defs::provider::internal::with_internal_providers_included;
+    use crate::interpreter::rule_defs::provider::internal::AuditProviderCollection;
+    use crate::interpreter::rule_defs::provider::protocol::lookup_provider_protocol;
+    use crate::interpreter::rule_defs::provider::protocol::register_provider_protocol;
+    use crate::interpreter::rule_defs::provider::protocol::ProviderProtocol;
+    use crate::interpreter::rule_defs::provider::registration::register_builtin_providers;
+    use crate::interpreter::rule_defs::provider::schema::ProviderSchema;
+    use crate::interpreter::rule_defs::provider::testing::FrozenProviderCollectionValueExt;
+    use crate::interpreter::rule_defs::provider::DefaultInfoCallable;
+    use crate::interpreter::rule_defs::provider::ProviderCollection;
+    use crate::interpreter::rule_defs::provider::ValueAsProviderLike;
+    use crate::interpreter::rule_defs::register_rule_defs;
+
+    /// Soft errors fired by tests in this module, as `category: message`.
+    fn soft_errors() -> &'static Mutex<Vec<String>> {
+        static SOFT_ERRORS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            buck2_core::error::initialize(Box::new(
+                |category, err, _loc, _quiet, _annotation, _severity, _backtrace| {
+                    SOFT_ERRORS
+                        .lock()
+                        .unwrap()
+                        .push(format!("{}: {:#}", category, err));
+                },
+            ))
+            .unwrap();
+        });
+        &SOFT_ERRORS
+    }
+
+    fn testing_globals() -> Globals {
+        GlobalsBuilder::extended()
+            .with(register_builtin_providers)
+            .with(register_provider)
+            .build()
+    }
+
+    /// The providers of `defs1.bzl` in `provider_collection_tester`.
+    const DEFS: &str = indoc!(
+        r#"
+        FooInfo = provider(fields=["foo"])
+        BarInfo = provider(fields=["bar"])
+        BazInfo = provider(fields=["baz"])
+        "#
+    );
+
+    /// A frozen collection of the provider list `providers`, which can use the providers of
+    /// `DEFS`. Collections built this way share provider ids, so they can be compared.
+    fn collection_with_defs(providers: &str) -> FrozenProviderCollectionValue {
+        FrozenProviderCollectionValue::testing_new(&format!("{}{}", DEFS, providers))
+    }
+
+    fn provider_collection_tester() -> SharedResult<Tester> {
+        let mut tester = Tester::new()?;
+        tester.additional_globals(collection_creator);
+        tester.additional_globals(artifactory);
+        tester.additional_globals(register_rule_defs);
+        tester.additional_globals(register_provider);
+        tester.add_import(&ImportPath::testing_new("root//provider:defs1.bzl"), DEFS)?;
+        tester.add_import(
+            &ImportPath::testing_new("root//provider:defs2.bzl"),
+            indoc!(
+                r#"
+                    load("//provider:defs1.bzl", "FooInfo", "BarInfo", "BazInfo")
+                    foo1 = FooInfo(foo="foo1")
+                    foo2 = FooInfo(foo="foo2")
+                    bar1 = BarInfo(bar="bar1")
+                    baz1 = BazInfo(baz="baz1")
+                    "#
+            ),
+        )?;
+
+        Ok(tester)
+    }
+
+    #[test]
+    fn provider_collection_constructs_properly() -> SharedResult<()> {
+        let mut tester = provider_collection_tester()?;
+        tester.run_starlark_bzl_test(indoc!(
+            r#"
+            load("//provider:defs1.bzl", "FooInfo", "BarInfo", "BazInfo")
+            load("//provider:defs2.bzl", "foo1", "bar1")
+            def test():
+                col = create_collection([foo1, bar1, DefaultInfo()])
+                assert_eq(None, col.get(BazInfo))
+                assert_eq("foo1", col[FooInfo].foo)
+                assert_eq("bar1", col[BarInfo].bar)
+                assert_eq([], col[DefaultInfo].default_outputs)
+            "#
+        ))?;
+        Ok(())
+    }
+
+    #[test]
+    fn provider_collection_fails_to_construct_on_bad_data() -> SharedResult<()> {
+        let mut tester = provider_collection_tester()?;
+        let not_a_list = indoc!(
+            r#"
+            load("//provider:defs2.bzl", "foo1")
+            def test():
+                create_collection(foo1)
+            "#
+        );
+        expect_error(
+            tester.run_starlark_bzl_test(not_a_list),
+            not_a_list,
+            "expected a list of Provider objects",
+        );
+
+        let mut tester = provider_collection_tester()?;
+        let not_a_provider = indoc!(
+            r#"
+            load("//provider:defs2.bzl", "foo1", "bar1")
+            def test():
+                create_collection([foo1, bar1, "not a provider", DefaultInfo()])
+            "#
+        );
+        expect_error(
+            tester.run_starlark_bzl_test(not_a_provider),
+            not_a_provider,
+            "expected a Provider object",
+        );
+
+        let mut tester = provider_collection_tester()?;
+        let duplicate_provider_types = indoc!(
+            r#"
+            load("//provider:defs2.bzl", "foo1", "foo2", "bar1")
+            def test():
+                create_collection([foo1, bar1, foo2, DefaultInfo()])
+            "#
+        );
+        expect_error(
+            tester.run_starlark_bzl_test(duplicate_provider_types),
+            duplicate_provider_types,
+            "specified twice",
+        );
+
+        let mut tester = provider_collection_tester()?;
+        let missing_default_info = indoc!(
+            r#"
+            load("//provider:defs2.bzl", "foo1", "bar1")
+            def test():
+                create_collection([foo1, bar1])
+            "#
+        );
+        expect_error(
+            tester.run_starlark_bzl_test(missing_default_info),
+            missing_default_info,
+            "did not receive a DefaultInfo",
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn returns_default_info() -> SharedResult<()> {
+        let mut tester = provider_collection_tester()?;
+        tester.run_starlark_bzl_test(indoc!(
+            r#"
+            artifact = source_artifact("foo", "bar.cpp")
+            frozen_collection = create_collection([
+                DefaultInfo(
+                    sub_targets={"foo": []},
+                    default_outputs=[artifact]
+                )
+            ])
+            def test():
+                di_sub_targets = get_default_info_sub_targets(frozen_collection)
+                di_default_outputs = get_default_info_default_outputs(frozen_collection)
+                assert_eq([], di_sub_targets["foo"][DefaultInfo].default_outputs)
+                assert_eq([artifact], di_default_outputs)
+            "#
+        ))
+    }
+
+    #[test]
+    fn provider_collection_contains_methods_and_in_operator() -> SharedResult<()> {
+        let mut tester = provider_collection_tester()?;
+        tester.add_import(
+            &ImportPath::testing_new("root//providers:defs.bzl"),
+            indoc!(
+                r#"
+                FooInfo = provider(fields=["foo"])
+                BarInfo = provider(fields=["bar"])
+                "#
+            ),
+        )?;
+        tester.run_starlark_bzl_test(indoc!(
+            r#"
+            load("//providers:defs.bzl", "FooInfo", "BarInfo")
+            c1 = create_collection([DefaultInfo(), FooInfo(foo="f1")])
+            c2 = create_collection([DefaultInfo(), FooInfo(foo="f2"), BarInfo(bar="b2")])
+            def test():
+                assert_eq(True, contains_provider(c1, FooInfo))
+                assert_eq(True, FooInfo in c1)
+                assert_eq(False, contains_provider(c1, BarInfo))
+                assert_eq(False, BarInfo in c1)
+                assert_eq(["DefaultInfo", "FooInfo", "BarInfo"], providers_list(c2))
+            "#
+        ))
+    }
+
+    #[test]
+    fn provider_collection_get() -> SharedResult<()> {
+        let mut tester = provider_collection_tester()?;
+        tester.add_import(
+            &ImportPath::testing_new("root//providers:defs.bzl"),
+            indoc!(
+                r#"
+                FooInfo = provider(fields=["foo"])
+                BarInfo = provider(fields=["bar"])
+
+                # Frozen collection
+                cf = create_collection([DefaultInfo(), FooInfo(foo="f1")])
+                "#
+            ),
+        )?;
+        tester.run_starlark_bzl_test(indoc!(
+            r#"
+            load("//providers:defs.bzl", "FooInfo", "BarInfo", "cf")
+
+            # Unfrozen collection
+            cu = create_collection([DefaultInfo(), FooInfo(foo="f1")])
+
+            def test():
+                assert_eq("f1", cu.get(FooInfo).foo)
+                assert_eq(None, cu.get(BarInfo))
+                assert_eq("f1", cf.get(FooInfo).foo)
+                assert_eq(None, cf.get(BarInfo))
+            "#
+        ))
+    }
+
+    #[test]
+    fn summary_is_send() {
+        fn assert_send<T: Send + 'static>(_: &T) {}
+
+        let collection = FrozenProviderCollectionValue::testing_new(indoc!(
+            r#"
+            FooInfo = provider(fields=["foo"])
+            [DefaultInfo(), FooInfo(foo="f1")]
+            "#
+        ));
+        let summary = collection.provider_collection().summary();
+        assert_send(&summary);
+
+        assert_eq!(
+            vec!["DefaultInfo", "FooInfo"],
+            summary
+                .providers
+                .iter()
+                .map(|p| p.name.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![("foo".to_owned(), "\"f1\"".to_owned())],
+            summary.providers[1].fields
+        );
+    }
+
+    #[test]
+    fn at_not_found_truncates_keys() {
+        let keys: Vec<String> = (0..30).rev().map(|i| format!("Info{:02}", i)).collect();
+
+        let truncated = format_provider_keys_truncated(&keys, Some(25));
+        assert!(truncated.starts_with("[`Info00`, `Info01`,"));
+        assert!(truncated.contains("`Info24`]"));
+        assert!(!truncated.contains("Info25"));
+        assert!(truncated.ends_with(" ...and 5 more"));
+
+        let all = format_provider_keys_truncated(&keys, None);
+        assert!(all.contains("`Info29`]"));
+        assert!(!all.contains("more"));
+    }
+
+    #[test]
+    fn default_info_only() {
+        let collection = FrozenProviderCollectionValue::testing_new(indoc!(
+            r#"
+            FooInfo = provider(fields=["foo"])
+            [DefaultInfo(), FooInfo(foo="f1")]
+            "#
+        ));
+        let default_info = collection.provider_collection().default_info_value();
+        let projected = collection.default_info_only();
+        // The projection keeps the heap of the original collection alive.
+        drop(collection);
+
+        let projected = projected.provider_collection();
+        assert_eq!(vec!["DefaultInfo"], projected.provider_names());
+        assert!(
+            projected
+                .default_info_value()
+                .to_value()
+                .ptr_eq(default_info.to_value())
+        );
+    }
+
+    #[test]
+    fn lazy_collection_runs_producer_at_most_once() -> anyhow::Result<()> {
+        let env = Module::new();
+        let globals = testing_globals();
+        let value = coerce::testing::to_value(
+            &env,
+            &globals,
+            indoc!(
+                r#"
+                FooInfo = provider(fields=["foo"])
+                [DefaultInfo(), FooInfo(foo="f1")]
+                "#
+            ),
+        );
+
+        let runs = Cell::new(0);
+        let lazy = LazyProviderCollection::new(|| {
+            runs.set(runs.get() + 1);
+            Ok(value)
+        });
+        assert_eq!(0, runs.get());
+
+        assert!(lazy.contains_provider(DefaultInfoCallable::provider_id())?);
+        assert_eq!(2, lazy.force()?.providers.len());
+        assert_eq!(1, runs.get());
+
+        let missing_default_info =
+            LazyProviderCollection::new(|| Ok(env.heap().alloc(Vec::<i32>::new())));
+        for _ in 0..2 {
+            let err = missing_default_info.force().err().unwrap();
+            assert!(format!("{:#}", err).contains("did not receive a DefaultInfo"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn subtarget_bearing_providers() {
+        let without_sub_targets = FrozenProviderCollectionValue::testing_new("[DefaultInfo()]");
+        assert!(
+            without_sub_targets
+                .provider_collection()
+                .subtarget_bearing_providers()
+                .is_empty()
+        );
+
+        let with_sub_targets = FrozenProviderCollectionValue::testing_new(
+            r#"[DefaultInfo(sub_targets={"foo": [DefaultInfo()]})]"#,
+        );
+        assert_eq!(
+            vec!["DefaultInfo"],
+            with_sub_targets
+                .provider_collection()
+                .subtarget_bearing_providers()
+                .iter()
+                .map(|id| id.name())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn provider_collection_symmetric_diff() -> SharedResult<()> {
+        let mut tester = provider_collection_tester()?;
+        tester.run_starlark_bzl_test(indoc!(
+            r#"
+            load("//provider:defs1.bzl", "FooInfo", "BarInfo", "BazInfo")
+            c1 = create_collection([DefaultInfo(), FooInfo(foo="f1"), BarInfo(bar="b1")])
+            c2 = create_collection([DefaultInfo(), BazInfo(baz="z2"), BarInfo(bar="b2")])
+            c3 = create_collection([DefaultInfo()])
+            def test():
+                assert_eq(["BazInfo", "FooInfo"], providers_symmetric_diff(c1, c2))
+                assert_eq(["BarInfo", "FooInfo"], providers_symmetric_diff(c3, c1))
+                assert_eq([], providers_symmetric_diff(c1, c1))
+            "#
+        ))
+    }
+
+    #[test]
+    fn filtering_preserves_provider_order() {
+        let collection = FrozenProviderCollectionValue::testing_new(indoc!(
+            r#"
+            AInfo = provider(fields=["a"])
+            BInfo = provider(fields=["b"])
+            CInfo = provider(fields=["c"])
+            [AInfo(a=1), BInfo(b=2), CInfo(c=3), DefaultInfo()]
+            "#
+        ));
+        let filtered = collection
+            .provider_collection()
+            .filtered(|id, _| id.name != "BInfo");
+        assert_eq!(
+            vec!["AInfo", "CInfo", "DefaultInfo"],
+            filtered.provider_names()
+        );
+    }
+
+    #[test]
+    fn try_from_value_with_required() -> anyhow::Result<()> {
+        let env = Module::new();
+        let globals = testing_globals();
+        let value = coerce::testing::to_value(
+            &env,
+            &globals,
+            indoc!(
+                r#"
+                RequiredInfo = provider(fields=[])
+                OtherInfo = provider(fields=[])
+                [[RequiredInfo(), OtherInfo()], [OtherInfo(), DefaultInfo()]]
+                "#
+            ),
+        );
+        let lists: Vec<Value> = ListRef::from_value(value).unwrap().iter().collect();
+        let required_id = ListRef::from_value(lists[0]).unwrap()[0]
+            .as_provider()
+            .unwrap()
+            .id()
+            .dupe();
+
+        let collection = ProviderCollection::try_from_value_with_required(lists[0], &required_id)?;
+        assert_eq!(2, collection.providers.len());
+        assert!(ProviderCollection::try_from_value(lists[0]).is_err());
+
+        let err =
+            ProviderCollection::try_from_value_with_required(lists[1], &required_id).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("did not receive the required provider `RequiredInfo`"),
+            "{}",
+            err
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn freeze_checks_default_info() {
+        let env = Module::new();
+        let bad = ProviderCollection {
+            providers: [(
+                DefaultInfoCallable::provider_id_t().interned(),
+                env.heap().alloc(1),
+            )]
+            .into_iter()
+            .collect(),
+            default_info_synthesized: false,
+        };
+        env.set("bad", env.heap().alloc(bad));
+        let err = env.freeze().unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("should have been `DefaultInfo` was not. It was `1`"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn default_info_synthesized() -> anyhow::Result<()> {
+        let env = Module::new();
+        let globals = testing_globals();
+        let default_info = || coerce::testing::to_value(&env, &globals, "DefaultInfo()");
+
+        let explicit = ProviderCollection::try_from_value_with_default_info(
+            coerce::testing::to_value(&env, &globals, "[DefaultInfo()]"),
+            default_info,
+        )?;
+        assert!(!explicit.was_default_info_synthesized());
+
+        let synthesized = ProviderCollection::try_from_value_with_default_info(
+            coerce::testing::to_value(&env, &globals, "[]"),
+            default_info,
+        )?;
+        assert!(synthesized.was_default_info_synthesized());
+
+        let frozen = FrozenProviderCollectionValue::testing_new("[DefaultInfo()]");
+        assert!(!frozen.provider_collection().was_default_info_synthesized());
+
+        Ok(())
+    }
+
+    #[test]
+    fn collect_provider_across_collections() {
+        let c1 =
+            FrozenProviderCollectionValue::testing_new(r#"[DefaultInfo(), RunInfo(args=["a"])]"#);
+        let c2 = FrozenProviderCollectionValue::testing_new("[DefaultInfo()]");
+        let c3 =
+            FrozenProviderCollectionValue::testing_new(r#"[DefaultInfo(), RunInfo(args=["c"])]"#);
+
+        let run_infos = collect_provider_across(
+            &[
+                c1.provider_collection(),
+                c2.provider_collection(),
+                c3.provider_collection(),
+            ],
+            RunInfoCallable::provider_id_t(),
+        );
+        assert_eq!(
+            vec![true, false, true],
+            run_infos.iter().map(|r| r.is_some()).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn canonical_bytes_ignore_provider_order() -> anyhow::Result<()> {
+        let c1 = collection_with_defs(r#"[DefaultInfo(), FooInfo(foo="f1"), BarInfo(bar="b1")]"#);
+        let c2 = collection_with_defs(r#"[BarInfo(bar="b1"), DefaultInfo(), FooInfo(foo="f1")]"#);
+        let c3 = collection_with_defs(r#"[DefaultInfo(), FooInfo(foo="f2"), BarInfo(bar="b1")]"#);
+
+        let bytes = c1.provider_collection().to_canonical_bytes()?;
+        assert_eq!(bytes, c2.provider_collection().to_canonical_bytes()?);
+        assert_ne!(bytes, c3.provider_collection().to_canonical_bytes()?);
+        Ok(())
+    }
+
+    #[test]
+    fn construction_observer_reports_provider_count() {
+        static OBSERVED: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+        set_collection_construction_observer(Box::new(|timing: &CollectionConstructionTiming| {
+            OBSERVED.lock().unwrap().push(timing.provider_count)
+        }));
+
+        FrozenProviderCollectionValue::testing_new(indoc!(
+            r#"
+            AInfo = provider(fields=["a"])
+            BInfo = provider(fields=["b"])
+            CInfo = provider(fields=["c"])
+            DInfo = provider(fields=["d"])
+            [DefaultInfo(), AInfo(a=1), BInfo(b=2), CInfo(c=3), DInfo(d=4)]
+            "#
+        ));
+
+        assert!(OBSERVED.lock().unwrap().contains(&5));
+    }
+
+    #[test]
+    fn construction_errors_are_collected() {
+        static COLLECTOR: Lazy<ConstructionErrorCollector> = Lazy::new(|| {
+            let collector = ConstructionErrorCollector::new();
+            init_construction_error_sink(collector.sink());
+            collector
+        });
+
+        let env = Module::new();
+        let globals = testing_globals();
+        let label = ProvidersLabel::testing_new("root", "pkg", "malformed", None)
+            .configure(ConfigurationData::testing_new());
+        let target = label.target();
+        let value = |code: &str| coerce::testing::to_value(&env, &globals, code);
+
+        Lazy::force(&COLLECTOR);
+        assert!(ProviderCollection::try_from_value_for_target(value("1"), target).is_err());
+        assert!(ProviderCollection::try_from_value_for_target(value("[1]"), target).is_err());
+        assert!(ProviderCollection::try_from_value_for_target(value("[]"), target).is_err());
+        assert!(
+            ProviderCollection::try_from_value_for_target(value("[DefaultInfo()]"), target).is_ok()
+        );
+        assert!(
+            ProviderCollection::try_from_value_with_default_info(value("[2]"), || value(
+                "DefaultInfo()"
+            ))
+            .is_err()
+        );
+
+        let collected: Vec<CollectedConstructionError> = COLLECTOR
+            .drain()
+            .into_iter()
+            .filter(|e| e.target.as_ref() == Some(target) || e.message.contains("got 2"))
+            .collect();
+        assert_eq!(
+            vec![
+                CollectedConstructionError {
+                    target: Some(target.dupe()),
+                    message: "expected a list of Provider objects, got 1".to_owned(),
+                },
+                CollectedConstructionError {
+                    target: Some(target.dupe()),
+                    message: "expected a Provider object, got 1".to_owned(),
+                },
+                CollectedConstructionError {
+                    target: Some(target.dupe()),
+                    message: "collection [] did not receive a DefaultInfo provider".to_owned(),
+                },
+                CollectedConstructionError {
+                    target: None,
+                    message: "expected a Provider object, got 2".to_owned(),
+                },
+            ],
+            collected
+        );
+        assert_eq!(Vec::<CollectedConstructionError>::new(), COLLECTOR.drain());
+    }
+
+    #[test]
+    fn provider_collection_providers_by_file() -> SharedResult<()> {
+        let mut tester = provider_collection_tester()?;
+        tester.add_import(
+            &ImportPath::testing_new("root//provider:defs3.bzl"),
+            indoc!(
+                r#"
+                QuxInfo = provider(fields=["qux"])
+                "#
+            ),
+        )?;
+        tester.run_starlark_bzl_test(indoc!(
+            r#"
+            load("//provider:defs1.bzl", "FooInfo", "BarInfo")
+            load("//provider:defs3.bzl", "QuxInfo")
+            c = create_collection([DefaultInfo(), FooInfo(foo="f"), QuxInfo(qux="q"), BarInfo(bar="b")])
+            def test():
+                assert_eq(
+                    {
+                        "root//provider/defs1.bzl": ["FooInfo", "BarInfo"],
+                        "root//provider/defs3.bzl": ["QuxInfo"],
+                    },
+                    providers_by_file(c),
+                )
+            "#
+        ))
+    }
+
+    #[test]
+    fn provider_collection_providers_from_file() -> SharedResult<()> {
+        let mut tester = provider_collection_tester()?;
+        tester.add_import(
+            &ImportPath::testing_new("root//provider:defs3.bzl"),
+            indoc!(
+                r#"
+                QuxInfo = provider(fields=["qux"])
+                "#
+            ),
+        )?;
+        tester.run_starlark_bzl_test(indoc!(
+            r#"
+            load("//provider:defs1.bzl", "FooInfo", "BarInfo")
+            load("//provider:defs3.bzl", "QuxInfo")
+            c = create_collection([DefaultInfo(), FooInfo(foo="f"), QuxInfo(qux="q"), BarInfo(bar="b")])
+            def test():
+                assert_eq(["FooInfo", "BarInfo"], providers_from_file(c, "root//provider/defs1.bzl"))
+                assert_eq(["QuxInfo"], providers_from_file(c, "root//provider/defs3.bzl"))
+                assert_eq([], providers_from_file(c, "root//provider/defs2.bzl"))
+            "#
+        ))
+    }
+
+    #[test]
+    fn provider_collection_all_defining_files_recursive() -> SharedResult<()> {
+        let mut tester = provider_collection_tester()?;
+        tester.add_import(
+            &ImportPath::testing_new("root//provider:defs3.bzl"),
+            indoc!(
+                r#"
+                QuxInfo = provider(fields=["qux"])
+                "#
+            ),
+        )?;
+        tester.run_starlark_bzl_test(indoc!(
+            r#"
+            load("//provider:defs1.bzl", "FooInfo", "BarInfo")
+            load("//provider:defs3.bzl", "QuxInfo")
+            c = create_collection([
+                DefaultInfo(sub_targets = {
+                    "qux": [DefaultInfo(sub_targets = {"nested": [QuxInfo(qux="q")]})],
+                    "bar": [BarInfo(bar="b")],
+                }),
+                FooInfo(foo="f"),
+            ])
+            plain = create_collection([DefaultInfo()])
+            def test():
+                assert_eq(
+                    ["root//provider/defs1.bzl", "root//provider/defs3.bzl"],
+                    all_defining_files_recursive(c),
+                )
+                assert_eq([], all_defining_files_recursive(plain))
+            "#
+        ))
+    }
+
+    #[test]
+    fn provider_collection_get_warn() -> SharedResult<()> {
+        let soft_errors = soft_errors();
+        let mut tester = provider_collection_tester()?;
+        tester.run_starlark_bzl_test(indoc!(
+            r#"
+            load("//provider:defs1.bzl", "FooInfo", "BarInfo")
+            c = create_collection([DefaultInfo(), FooInfo(foo="f1")])
+            def test():
+                assert_eq("f1", c.get_warn(FooInfo).foo)
+                assert_eq(None, c.get_warn(BarInfo))
+            "#
+        ))?;
+
+        let fired: Vec<String> = soft_errors
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|e| e.starts_with("expected_provider_missing"))
+            .cloned()
+            .collect();
+        assert_eq!(1, fired.len(), "{:?}", fired);
+        assert!(fired[0].contains("`BarInfo`"));
+        Ok(())
+    }
+
+    #[test]
+    fn debug_is_stable() {
+        let collection = FrozenProviderCollectionValue::testing_new(indoc!(
+            r#"
+            FooInfo = provider(fields=["foo"])
+            [DefaultInfo(), FooInfo(foo="f1")]
+            "#
+        ));
+        let debug = format!("{:?}", collection.provider_collection());
+        assert!(
+            debug.starts_with("ProviderCollection { DefaultInfo: "),
+            "{}",
+            debug
+        );
+        assert!(debug.contains(r#"FooInfo: FooInfo(foo="f1")"#), "{}", debug);
+        assert!(!debug.contains("0x"), "{}", debug);
+    }
+
+    #[test]
+    fn interned_provider_ids() {
+        let value = FrozenProviderCollectionValue::testing_new("[DefaultInfo(), RunInfo()]");
+        let collection = value.provider_collection();
+
+        let run_info = RunInfoCallable::provider_id_t();
+        let run_info_copy = (**run_info.id()).clone();
+        assert_eq!(run_info.interned(), InternedProviderId::new(&run_info_copy));
+        assert!(collection.contains_interned_provider(run_info.interned()));
+        assert_eq!(
+            collection.get_provider_raw(&run_info_copy),
+            collection.get_interned_provider_raw(run_info.interned())
+        );
+
+        let never_interned = ProviderId {
+            path: None,
+            name: "NeverInternedInfo".to_owned(),
+        };
+        assert_eq!(None, InternedProviderId::get(&never_interned));
+        assert!(!collection.contains_provider(&never_interned));
+        let interned = InternedProviderId::new(&never_interned);
+        assert_eq!(Some(interned), InternedProviderId::get(&never_interned));
+        assert!(!collection.contains_interned_provider(interned));
+
+        // Callables carry their interned id, so lookups don't go through the interner.
+        let env = Module::new();
+        let foo_info = coerce::testing::to_value(
+            &env,
+            &testing_globals(),
+            "FooInfo = provider(fields=[])\nFooInfo",
+        );
+        let foo_info = foo_info.as_provider_callable().unwrap();
+        assert_eq!(
+            Some(InternedProviderId::new(foo_info.id().unwrap())),
+            foo_info.interned_id()
+        );
+        assert_eq!(
+            Some(run_info.interned()),
+            RunInfoCallable::new().interned_id()
+        );
+    }
+
+    #[test]
+    fn provider_collection_assert_only() -> SharedResult<()> {
+        let mut tester = provider_collection_tester()?;
+        tester.run_starlark_bzl_test(indoc!(
+            r#"
+            load("//provider:defs1.bzl", "FooInfo", "BarInfo")
+            def test():
+                c = create_collection([DefaultInfo(), FooInfo(foo="f1")])
+                c.assert_only([FooInfo, BarInfo])
+                create_collection([DefaultInfo()]).assert_only([])
+            "#
+        ))?;
+
+        let mut tester = provider_collection_tester()?;
+        let extra_provider = indoc!(
+            r#"
+            load("//provider:defs1.bzl", "FooInfo", "BarInfo", "BazInfo")
+            def test():
+                c = create_collection([DefaultInfo(), FooInfo(foo="f1"), BazInfo(baz="z1")])
+                c.assert_only([FooInfo, BarInfo])
+            "#
+        );
+        expect_error(
+            tester.run_starlark_bzl_test(extra_provider),
+            extra_provider,
+            "providers that are not allowed: BazInfo",
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn dedup_identical_providers() -> anyhow::Result<()> {
+        let env = Module::new();
+        let globals = testing_globals();
+        let identical = || {
+            coerce::testing::to_value(
+                &env,
+                &globals,
+                indoc!(
+                    r#"
+                    run_info = RunInfo(args=["a"])
+                    [DefaultInfo(), run_info, run_info]
+                    "#
+                ),
+            )
+        };
+        let conflicting = || {
+            coerce::testing::to_value(
+                &env,
+                &globals,
+                r#"[DefaultInfo(), RunInfo(args=["a"]), RunInfo(args=["b"])]"#,
+            )
+        };
+
+        let deduped = ProviderCollection::try_from_value_dedup_identical(identical())?;
+        assert_eq!(2, deduped.providers.len());
+        assert!(ProviderCollection::try_from_value(identical()).is_err());
+
+        let err = ProviderCollection::try_from_value_dedup_identical(conflicting())
+            .err()
+            .unwrap();
+        assert!(
+            format!("{:#}", err).contains("specified twice"),
+            "{:#}",
+            err
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn provider_collection_sub() -> SharedResult<()> {
+        let mut tester = provider_collection_tester()?;
+        tester.run_starlark_bzl_test(indoc!(
+            r#"
+            load("//provider:defs1.bzl", "FooInfo", "BarInfo", "BazInfo")
+            def test():
+                c = create_collection([DefaultInfo(), FooInfo(foo="f1"), BarInfo(bar="b1")])
+                removed = c - FooInfo
+                assert_eq(False, FooInfo in removed)
+                assert_eq("b1", removed[BarInfo].bar)
+                assert_eq(True, FooInfo in c)
+                removed_absent = removed - BazInfo
+                assert_eq(False, FooInfo in removed_absent)
+                assert_eq("b1", removed_absent[BarInfo].bar)
+            "#
+        ))?;
+
+        let mut tester = provider_collection_tester()?;
+        let remove_default_info = indoc!(
+            r#"
+            def test():
+                create_collection([DefaultInfo()]) - DefaultInfo
+            "#
+        );
+        expect_error(
+            tester.run_starlark_bzl_test(remove_default_info),
+            remove_default_info,
+            "cannot remove `DefaultInfo`",
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reachable_labels() -> anyhow::Result<()> {
+        let collection = FrozenProviderCollectionValue::testing_new(indoc!(
+            r#"
+            [
+                DefaultInfo(
+                    sub_targets = {
+                        "foo": [DefaultInfo(sub_targets = {"nested": [DefaultInfo()]})],
+                        "bar": [DefaultInfo()],
+                    },
+                ),
+            ]
+            "#
+        ));
+        let base = ProvidersLabel::testing_new("root", "pkg", "target", None)
+            .configure(ConfigurationData::testing_new());
+        let expected: Vec<_> = [
+            None,
+            Some(&["foo"][..]),
+            Some(&["foo", "nested"][..]),
+            Some(&["bar"][..]),
+        ]
+        .into_iter()
+        .map(|name| {
+            ProvidersLabel::testing_new("root", "pkg", "target", name)
+                .configure(ConfigurationData::testing_new())
+        })
+        .collect();
+        assert_eq!(expected, collection.reachable_labels(&base)?);
+
+        let nested_base = ProvidersLabel::testing_new("root", "pkg", "target", Some(&["foo"]))
+            .configure(ConfigurationData::testing_new());
+        assert_eq!(
+            vec![
+                nested_base.clone(),
+                ProvidersLabel::testing_new("root", "pkg", "target", Some(&["foo", "nested"]))
+                    .configure(ConfigurationData::testing_new()),
+            ],
+            collection
+                .lookup_inner(&nested_base)?
+                .reachable_labels(&nested_base)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reachable_labels_through_shared_default_info() -> anyhow::Result<()> {
+        let collection = FrozenProviderCollectionValue::testing_new(indoc!(
+            r#"
+            shared = DefaultInfo(sub_targets = {"x": [DefaultInfo()]})
+            [DefaultInfo(sub_targets = {"a": [shared], "b": [shared]})]
+            "#
+        ));
+        let base = ProvidersLabel::testing_new("root", "pkg", "target", None)
+            .configure(ConfigurationData::testing_new());
+        let expected: Vec<_> = [
+            None,
+            Some(&["a"][..]),
+            Some(&["a", "x"][..]),
+            Some(&["b"][..]),
+            Some(&["b", "x"][..]),
+        ]
+        .into_iter()
+        .map(|name| {
+            ProvidersLabel::testing_new("root", "pkg", "target", name)
+                .configure(ConfigurationData::testing_new())
+        })
+        .collect();
+        assert_eq!(expected, collection.reachable_labels(&base)?);
+        Ok(())
+    }
+
+    #[test]
+    fn rejected_flavors_are_tallied() {
+        let collection = FrozenProviderCollectionValue::testing_new("[DefaultInfo()]");
+        let target = ProvidersLabel::testing_new("root", "pkg", "target", None)
+            .configure(ConfigurationData::testing_new())
+            .target()
+            .dupe();
+        let lookup = |flavor: &str| {
+            let label = ConfiguredProvidersLabel::new(
+                target.dupe(),
+                ProvidersName::NonDefault(Box::new(NonDefaultProvidersName::UnrecognizedFlavor(
+                    flavor.into(),
+                ))),
+            );
+            let err = collection.lookup_inner(&label).unwrap_err();
+            assert!(err.to_string().contains("Cannot handle flavor"), "{}", err);
+        };
+
+        lookup("test_rejected_flavor_a");
+        lookup("test_rejected_flavor_b");
+        lookup("test_rejected_flavor_a");
+
+        let ours: Vec<(String, usize)> = rejected_flavors()
+            .into_iter()
+            .filter(|(flavor, _)| flavor.starts_with("test_rejected_flavor_"))
+            .collect();
+        assert_eq!(
+            vec![
+                ("test_rejected_flavor_a".to_owned(), 2),
+                ("test_rejected_flavor_b".to_owned(), 1),
+            ],
+            ours
+        );
+    }
+
+    #[test]
+    fn validate_against_schema() -> anyhow::Result<()> {
+        let schema = ProviderSchema::from_json(indoc!(
+            r#"
+            {
+                "providers": [
+                    {"name": "DefaultInfo"},
+                    {
+                        "name": "FooInfo",
+                        "fields": [
+                            {"name": "foo", "type": "string"},
+                            {"name": "bar", "type": "list"},
+                            {"name": "baz"}
+                        ]
+                    },
+                    {"name": "BarInfo", "fields": []}
+                ]
+            }
+            "#
+        ))?;
+
+        let conforming = FrozenProviderCollectionValue::testing_new(indoc!(
+            r#"
+            FooInfo = provider(fields=["foo", "bar", "baz"])
+            BarInfo = provider(fields=[])
+            [DefaultInfo(), FooInfo(foo="f", bar=[1], baz=None), BarInfo()]
+            "#
+        ));
+        conforming
+            .provider_collection()
+            .validate_against_schema(&schema)?;
+
+        let violating = FrozenProviderCollectionValue::testing_new(indoc!(
+            r#"
+            FooInfo = provider(fields=["foo", "bar"])
+            [DefaultInfo(), FooInfo(foo=1, bar=[1])]
+            "#
+        ));
+        let err = violating
+            .provider_collection()
+            .validate_against_schema(&schema)
+            .unwrap_err();
+        assert_eq!(
+            "provider collection does not match its schema: \
+            field `foo` of `FooInfo` should be of type `string`, got `int`; \
+            `FooInfo` has no field `baz`; \
+            missing provider `BarInfo`",
+            err.to_string()
+        );
+
+        assert!(ProviderSchema::from_json(r#"{"providers": [{"fields": []}]}"#).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn render_tree() {
+        let collection = FrozenProviderCollectionValue::testing_new(indoc!(
+            r#"
+            FooInfo = provider(fields=[])
+            BarInfo = provider(fields=[])
+            QuxInfo = provider(fields=[])
+            [
+                DefaultInfo(sub_targets = {
+                    "bar": [DefaultInfo(), BarInfo()],
+                    "qux": [DefaultInfo(sub_targets = {"nested": [DefaultInfo(), QuxInfo()]})],
+                }),
+                FooInfo(),
+            ]
+            "#
+        ));
+
+        assert_eq!(
+            indoc!(
+                r#"
+                DefaultInfo
+                  [bar]
+                    DefaultInfo
+                    BarInfo
+                  [qux]
+                    DefaultInfo
+                      [nested]
+                        DefaultInfo
+                        QuxInfo
+                FooInfo
+                "#
+            ),
+            collection.provider_collection().render_tree()
+        );
+    }
+
+    #[test]
+    fn provider_id_set() {
+        let a = collection_with_defs("[DefaultInfo(), FooInfo(), BarInfo()]");
+        let b = collection_with_defs("[BazInfo(), BarInfo(), DefaultInfo()]");
+        let a = a.provider_collection().provider_id_set();
+        let b = b.provider_collection().provider_id_set();
+        let names = |ids: Vec<&&ProviderId>| -> Vec<String> {
+            ids.into_iter().map(|id| id.name.clone()).collect()
+        };
+
+        assert_eq!(
+            vec!["DefaultInfo", "BarInfo"],
+            names(a.intersection(&b).collect())
+        );
+        assert_eq!(vec!["FooInfo"], names(a.difference(&b).collect()));
+        assert_eq!(4, a.union(&b).count());
+        assert_eq!(
+            a,
+            collection_with_defs("[BarInfo(), DefaultInfo(), FooInfo()]")
+                .provider_collection()
+                .provider_id_set()
+        );
+    }
+
+    #[test]
+    fn assert_mutually_exclusive() {
+        let satisfying = collection_with_defs("[DefaultInfo(), FooInfo(), BazInfo()]");
+        let violating = collection_with_defs("[DefaultInfo(), FooInfo(), BarInfo(), BazInfo()]");
+        let ids = violating.provider_collection().provider_ids();
+        let (foo_info, bar_info, baz_info) = (ids[1], ids[2], ids[3]);
+        let groups: &[&[&ProviderId]] = &[&[foo_info, bar_info], &[baz_info]];
+
+        assert!(
+            satisfying
+                .provider_collection()
+                .assert_mutually_exclusive(groups)
+                .is_ok()
+        );
+        let err = violating
+            .provider_collection()
+            .assert_mutually_exclusive(groups)
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("providers FooInfo, BarInfo are mutually exclusive"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn serialize_default_info_only() -> SharedResult<()> {
+        let mut tester = provider_collection_tester()?;
+        tester.run_starlark_bzl_test(indoc!(
+            r#"
+            load("//provider:defs2.bzl", "foo1")
+            frozen_collection = create_collection([
+                DefaultInfo(
+                    sub_targets={"sub": []},
+                    default_outputs=[source_artifact("foo", "bar.cpp")],
+                ),
+                foo1,
+            ])
+            def test():
+                serialized = serialize_default_info_only(frozen_collection)
+                assert_eq(True, "bar.cpp" in serialized)
+                assert_eq(True, "\"sub_targets\":[\"sub\"]" in serialized)
+                assert_eq(False, "foo1" in serialized)
+            "#
+        ))
+    }
+
+    #[test]
+    fn default_artifacts() -> SharedResult<()> {
+        let mut tester = provider_collection_tester()?;
+        tester.run_starlark_bzl_test(indoc!(
+            r#"
+            a = source_artifact("foo", "a.cpp")
+            b = source_artifact("foo", "b.cpp")
+            two = create_collection([DefaultInfo(default_outputs = [a, b])])
+            empty = create_collection([DefaultInfo()])
+            def test():
+                assert_eq(True, has_default_artifacts(two, [a, b]))
+                assert_eq(False, has_default_artifacts(two, [b, a]))
+                assert_eq(True, has_default_artifacts(empty, []))
+            "#
+        ))
+    }
+
+    #[test]
+    fn simplify() -> SharedResult<()> {
+        let mut tester = provider_collection_tester()?;
+        tester.run_starlark_bzl_test(indoc!(
+            r#"
+            load("//provider:defs2.bzl", "foo1")
+            single = source_artifact("foo", "single.cpp")
+            other = source_artifact("foo", "other.cpp")
+            c = create_collection([
+                DefaultInfo(sub_targets = {
+                    "single": [DefaultInfo(default_outputs = [single])],
+                    "empty": [DefaultInfo()],
+                    "two": [DefaultInfo(default_outputs = [single, other])],
+                    "other_outputs": [DefaultInfo(default_outputs = [single], other_outputs = [other])],
+                    "user_provider": [DefaultInfo(default_outputs = [single]), foo1],
+                    "nested": [DefaultInfo(
+                        default_outputs = [single],
+                        sub_targets = {"inner": [DefaultInfo(default_outputs = [other])]},
+                    )],
+                }),
+                foo1,
+            ])
+            plain = create_collection([DefaultInfo(default_outputs = [single])])
+            def test():
+                assert_eq(
+                    {
+                        "single": "output",
+                        "empty": "collection",
+                        "two": "collection",
+                        "other_outputs": "collection",
+                        "user_provider": "collection",
+                        "nested": "collection",
+                    },
+                    simplified_sub_targets(c),
+                )
+                assert_eq({}, simplified_sub_targets(plain))
+
+                serialized = serialize_simplified(c)
+                assert_eq(True, "\"single\":\"<source " in serialized)
+                assert_eq(True, "\"inner\":\"<source " in serialized)
+                assert_eq(True, "\"providers\":[\"DefaultInfo\",\"FooInfo\"]" in serialized)
+            "#
+        ))
+    }
+
+    #[test]
+    fn conforms_to_protocol() {
+        let conforming = collection_with_defs("[DefaultInfo(), FooInfo(), BarInfo()]");
+        let non_conforming = collection_with_defs("[DefaultInfo(), BarInfo()]");
+        register_provider_protocol(ProviderProtocol {
+            name: "library".to_owned(),
+            required: conforming.provider_collection().provider_ids()[1..]
+                .iter()
+                .map(|id| Arc::new((*id).clone()))
+                .collect(),
+        });
+        let protocol = lookup_provider_protocol("library").unwrap();
+        assert!(lookup_provider_protocol("binary").is_none());
+
+        assert_eq!(
+            Ok(()),
+            conforming.provider_collection().conforms_to(&protocol)
+        );
+        assert_eq!(
+            vec!["FooInfo"],
+            non_conforming
+                .provider_collection()
+                .conforms_to(&protocol)
+                .unwrap_err()
+                .iter()
+                .map(|id| id.name())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn remap_ids() {
+        let value = FrozenProviderCollectionValue::testing_new(indoc!(
+            r#"
+            FooInfo = provider(fields=[])
+            BarInfo = provider(fields=[])
+            [DefaultInfo(), FooInfo(), BarInfo()]
+            "#
+        ));
+        let collection = value.provider_collection();
+        let ids: Vec<Arc<ProviderId>> = collection
+            .provider_ids()
+            .into_iter()
+            .map(|id| Arc::new(id.clone()))
+            .collect();
+        let new_foo = Arc::new(ProviderId {
+            path: None,
+            name: "NewFooInfo".to_owned(),
+        });
+
+        let remapped = value
+            .remap_ids(&HashMap::from([(ids[1].dupe(), new_foo.dupe())]))
+            .unwrap();
+        assert_eq!(
+            vec!["DefaultInfo", "NewFooInfo", "BarInfo"],
+            remapped.provider_collection().provider_names()
+        );
+        assert_eq!(
+            collection.get_provider_raw(&ids[1]),
+            remapped.provider_collection().get_provider_raw(&new_foo)
+        );
+
+        let err = value
+            .remap_ids(&HashMap::from([(ids[1].dupe(), ids[2].dupe())]))
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("specify provider `BarInfo` twice"),
+            "{}",
+            err
+        );
+
+        // Swapping two providers is not a collision.
+        let swapped = value
+            .remap_ids(&HashMap::from([
+                (ids[1].dupe(), ids[2].dupe()),
+                (ids[2].dupe(), ids[1].dupe()),
+            ]))
+            .unwrap();
+        assert_eq!(
+            collection.get_provider_raw(&ids[1]),
+            swapped.provider_collection().get_provider_raw(&ids[2])
+        );
+
+        assert!(
+            value
+                .remap_ids(&HashMap::from([(ids[0].dupe(), new_foo.dupe())]))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn validate() {
+        let env = Module::new();
+        let globals = testing_globals();
+        let validate = |value: &str| {
+            ProviderCollection::validate(coerce::testing::to_value(&env, &globals, value))
+                .map_err(|errors| errors.iter().map(|e| e.to_string()).collect::<Vec<_>>())
+        };
+
+        assert_eq!(Ok(()), validate("[DefaultInfo(), RunInfo()]"));
+
+        let errors = validate(r#"[RunInfo(), "not a provider", RunInfo()]"#).unwrap_err();
+        assert_eq!(3, errors.len(), "{:?}", errors);
+        assert!(errors[0].contains("expected a Provider object"));
+        assert!(errors[1].contains("provider of type RunInfo specified twice"));
+        assert!(errors[2].contains("did not receive a DefaultInfo provider"));
+
+        let errors = validate("RunInfo()").unwrap_err();
+        assert_eq!(1, errors.len(), "{:?}", errors);
+        assert!(errors[0].contains("expected a list of Provider objects"));
+    }
+
+    #[test]
+    fn provider_collection_field_names() -> SharedResult<()> {
+        let mut tester = provider_collection_tester()?;
+        tester.add_import(
+            &ImportPath::testing_new("root//provider:defs3.bzl"),
+            indoc!(
+                r#"
+                FooBarInfo = provider(fields=["foo", "bar"])
+                "#
+            ),
+        )?;
+        tester.run_starlark_bzl_test(indoc!(
+            r#"
+            load("//provider:defs1.bzl", "BarInfo")
+            load("//provider:defs3.bzl", "FooBarInfo")
+            c = create_collection([DefaultInfo(), FooBarInfo(foo="f")])
+            def test():
+                assert_eq(["foo", "bar"], c.field_names(FooBarInfo))
+                assert_eq(False, "bar" in c.field_names(DefaultInfo))
+            "#
+        ))?;
+
+        let mut tester = provider_collection_tester()?;
+        let missing = indoc!(
+            r#"
+            load("//provider:defs1.bzl", "BarInfo")
+            def test():
+                create_collection([DefaultInfo()]).field_names(BarInfo)
+            "#
+        );
+        expect_error(
+            tester.run_starlark_bzl_test(missing),
+            missing,
+            "does not have a key `BarInfo`",
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn partial_provider_collection() -> anyhow::Result<()> {
+        let value =
+            FrozenProviderCollectionValue::testing_new(r#"[DefaultInfo(), RunInfo(args=["a"])]"#);
+        let collection = value.provider_collection();
+        let run_info = value.owned(
+            *collection
+                .get_provider_raw(RunInfoCallable::provider_id())
+                .unwrap(),
+        );
+        let default_info = value.owned(collection.default_info_value());
+
+        let mut partial = PartialProviderCollection::new();
+        partial.add(run_info.dupe())?;
+        assert!(partial.contains_provider(RunInfoCallable::provider_id()));
+        assert!(partial.add(run_info.dupe()).is_err());
+        assert!(partial.add(default_info.dupe()).is_err());
+
+        let finalized = partial.finalize(default_info)?;
+        let finalized = finalized.provider_collection();
+        assert_eq!(vec!["DefaultInfo", "RunInfo"], finalized.provider_names());
+        assert!(!finalized.was_default_info_synthesized());
+
+        let mut partial = PartialProviderCollection::new();
+        partial.add(run_info.dupe())?;
+        let err = partial.finalize(run_info).unwrap_err();
+        assert!(
+            err.to_string().contains("should have been `DefaultInfo`"),
+            "{}",
+            err
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn provider_collection_output_signature() -> SharedResult<()> {
+        let mut tester = provider_collection_tester()?;
+        tester.run_starlark_bzl_test(indoc!(
+            r#"
+            load("//provider:defs1.bzl", "FooInfo")
+            a = source_artifact("foo", "a.cpp")
+            b = source_artifact("foo", "b.cpp")
+            c1 = create_collection([DefaultInfo(default_outputs=[a]), FooInfo(foo="1")])
+            c2 = create_collection([DefaultInfo(default_outputs=[a]), FooInfo(foo="2")])
+            c3 = create_collection([DefaultInfo(default_outputs=[b]), FooInfo(foo="1")])
+            c4 = create_collection([
+                DefaultInfo(default_outputs=[a], sub_targets={"sub": [DefaultInfo(default_outputs=[b])]}),
+            ])
+            def test():
+                assert_eq(output_signature(c1), output_signature(c2))
+                assert_eq(False, output_signature(c1) == output_signature(c3))
+                assert_eq(False, output_signature(c1) == output_signature(c4))
+            "#
+        ))
+    }
+
+    #[test]
+    fn deprecated_provider_soft_error() -> SharedResult<()> {
+        let soft_errors = soft_errors();
+        let mut tester = provider_collection_tester()?;
+        tester.add_import(
+            &ImportPath::testing_new("root//provider:deprecated.bzl"),
+            indoc!(
+                r#"
+                OldInfo = provider(fields=[])
+                "#
+            ),
+        )?;
+        tester.run_starlark_bzl_test(indoc!(
+            r#"
+            load("//provider:deprecated.bzl", "OldInfo")
+            def test():
+                deprecate_provider(OldInfo, "it is too old", "NewInfo")
+                create_collection([DefaultInfo(), OldInfo()])
+            "#
+        ))?;
+
+        let fired: Vec<String> = soft_errors
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|e| e.starts_with("deprecated_provider"))
+            .cloned()
+            .collect();
+        assert_eq!(
+            vec![
+                "deprecated_provider: `OldInfo` is deprecated: it is too old, use `NewInfo` instead"
+            ],
+            fired
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn assert_no_deprecated() {
+        let with_retired = FrozenProviderCollectionValue::testing_new(indoc!(
+            r#"
+            RetiredInfo = provider(fields=[])
+            CurrentInfo = provider(fields=[])
+            [DefaultInfo(), RetiredInfo(), CurrentInfo()]
+            "#
+        ));
+        let without_retired = FrozenProviderCollectionValue::testing_new(indoc!(
+            r#"
+            CurrentInfo = provider(fields=[])
+            [DefaultInfo(), CurrentInfo()]
+            "#
+        ));
+        assert!(
+            with_retired
+                .provider_collection()
+                .assert_no_deprecated()
+                .is_ok()
+        );
+
+        register_deprecated_provider(
+            with_retired.provider_collection().provider_ids()[1],
+            ProviderDeprecation {
+                reason: None,
+                replacement: Some("CurrentInfo".to_owned()),
+            },
+        );
+        let err = with_retired
+            .provider_collection()
+            .assert_no_deprecated()
+            .unwrap_err();
+        assert_eq!(
+            "deprecated providers are present: `RetiredInfo` is deprecated: use `CurrentInfo` instead",
+            err.to_string()
+        );
+        assert!(
+            without_retired
+                .provider_collection()
+                .assert_no_deprecated()
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn provider_type_summary() {
+        let collection = FrozenProviderCollectionValue::testing_new(indoc!(
+            r#"
+            FooInfo = provider(fields=["foo"])
+            [FooInfo(foo="f"), RunInfo(), DefaultInfo()]
+            "#
+        ));
+        assert_eq!(
+            vec![
+                ("DefaultInfo".to_owned(), "DefaultInfo".to_owned()),
+                ("FooInfo".to_owned(), "provider".to_owned()),
+                ("RunInfo".to_owned(), "RunInfo".to_owned()),
+            ],
+            collection.provider_collection().provider_type_summary()
+        );
+    }
+
+    #[test]
+    fn provider_callables() {
+        let soft_errors = soft_errors();
+        let collection = FrozenProviderCollectionValue::testing_new(indoc!(
+            r#"
+            FooInfo = provider(fields=["foo"])
+            [FooInfo(foo="f"), RunInfo(), DefaultInfo()]
+            "#
+        ));
+        let callables = collection.provider_collection().provider_callables();
+        let ids: Vec<&ProviderId> = callables
+            .iter()
+            .map(|c| {
+                c.to_value()
+                    .as_provider_callable()
+                    .and_then(|c| c.id())
+                    .map(|id| &**id)
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                &**DefaultInfoCallable::provider_id(),
+                &**RunInfoCallable::provider_id()
+            ],
+            ids
+        );
+        assert!(soft_errors.lock().unwrap().contains(
+            &"provider_callable_unavailable: cannot recover the callable of provider `FooInfo`, it is not a builtin provider"
+                .to_owned()
+        ));
+    }
+
+    #[test]
+    fn sharing_from() -> anyhow::Result<()> {
+        let dep_value = FrozenProviderCollectionValue::testing_new(indoc!(
+            r#"
+            FooInfo = provider(fields=[])
+            [DefaultInfo(), FooInfo()]
+            "#
+        ));
+        let dep = dep_value.provider_collection();
+        let extra =
+            FrozenProviderCollectionValue::testing_new(r#"[DefaultInfo(), RunInfo(args=["a"])]"#);
+        let run_info = extra.owned(
+            *extra
+                .provider_collection()
+                .get_provider_raw(RunInfoCallable::provider_id())
+                .unwrap(),
+        );
+
+        let shared = FrozenProviderCollectionValue::sharing_from(&dep_value, &[run_info])?;
+        let shared = shared.provider_collection();
+        assert_eq!(
+            vec!["DefaultInfo", "FooInfo", "RunInfo"],
+            shared.provider_names()
+        );
+        let foo_info = dep.provider_ids()[1];
+        assert_eq!(
+            dep.get_provider_raw(foo_info),
+            shared.get_provider_raw(foo_info)
+        );
+
+        let err = FrozenProviderCollectionValue::sharing_from(
+            &dep_value,
+            &[dep_value.owned(*dep.get_provider_raw(foo_info).unwrap())],
+        )
+        .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("provider of type FooInfo specified twice"),
+            "{}",
+            err
+        );
+        assert!(
+            FrozenProviderCollectionValue::sharing_from(
+                &dep_value,
+                &[extra.owned(extra.provider_collection().default_info_value())]
+            )
+            .is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn provider_collection_assert_freezes_to() -> SharedResult<()> {
+        let mut tester = provider_collection_tester()?;
+        tester.add_import(
+            &ImportPath::testing_new("root//provider:golden.bzl"),
+            indoc!(
+                r#"
+                load("//provider:defs2.bzl", "foo1", "bar1")
+                golden = create_collection([DefaultInfo(), foo1, bar1])
+                "#
+            ),
+        )?;
+        tester.run_starlark_bzl_test(indoc!(
+            r#"
+            load("//provider:defs1.bzl", "FooInfo", "BarInfo")
+            load("//provider:golden.bzl", "golden")
+            def test():
+                c = create_collection([BarInfo(bar="bar1"), DefaultInfo(), FooInfo(foo="foo1")])
+                assert_freezes_to(c, golden)
+                assert_eq("foo1", c[FooInfo].foo)
+            "#
+        ))?;
+
+        let mismatch = indoc!(
+            r#"
+            load("//provider:defs1.bzl", "FooInfo", "BarInfo")
+            load("//provider:golden.bzl", "golden")
+            def test():
+                assert_freezes_to(
+                    create_collection([DefaultInfo(), FooInfo(foo="foo2"), BarInfo(bar="bar1")]),
+                    golden,
+                )
+            "#
+        );
+        expect_error(
+            tester.run_starlark_bzl_test(mismatch),
+            mismatch,
+            "does not freeze to",
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn memory_by_provider() {
+        let collection = FrozenProviderCollectionValue::testing_new(indoc!(
+            r#"
+            SmallInfo = provider(fields=["a"])
+            BigInfo = provider(fields=["a", "b", "c", "d", "e", "f"])
+            [
+                DefaultInfo(),
+                SmallInfo(a=1),
+                BigInfo(a=1, b=2, c=3, d=4, e=5, f=6),
+            ]
+            "#
+        ));
+        let sizes = collection.provider_collection().memory_by_provider();
+        assert_eq!(
+            vec!["DefaultInfo", "SmallInfo", "BigInfo"],
+            sizes
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert!(sizes.iter().all(|(_, size)| *size > 0), "{:?}", sizes);
+        // Fields are owned by the provider, so each extra field is counted.
+        assert!(
+            sizes[2].1 >= sizes[1].1 + 5 * mem::size_of::<FrozenValue>(),
+            "{:?}",
+            sizes
+        );
+    }
+
+    #[test]
+    fn provider_collection_default_info_is_synthesized() -> SharedResult<()> {
+        let mut tester = provider_collection_tester()?;
+        tester.run_starlark_bzl_test(indoc!(
+            r#"
+            load("//provider:defs2.bzl", "foo1")
+            def test():
+                assert_eq(False, create_collection([DefaultInfo(), foo1]).default_info_is_synthesized())
+                info = DefaultInfo(sub_targets={"explicit": [DefaultInfo(), foo1], "synthesized": [foo1]})
+                assert_eq(False, info.sub_targets["explicit"].default_info_is_synthesized())
+                assert_eq(True, info.sub_targets["synthesized"].default_info_is_synthesized())
+            "#
+        ))?;
+        Ok(())
+    }
+
+    #[test]
+    fn diff_against_snapshot() -> anyhow::Result<()> {
+        let recorded =
+            collection_with_defs(r#"[DefaultInfo(), FooInfo(foo="f1"), BarInfo(bar="b1")]"#);
+        let current = collection_with_defs(
+            r#"[DefaultInfo(), FooInfo(foo="f2"), BarInfo(bar="b1"), RunInfo()]"#,
+        );
+        let snapshot = recorded.provider_collection().to_canonical_bytes()?;
+        let id = |collection: &FrozenProviderCollectionValue, i: usize| {
+            canonical_provider_id(collection.provider_collection().provider_ids()[i])
+        };
+
+        assert!(
+            recorded
+                .provider_collection()
+                .diff_against_snapshot(&snapshot)?
+                .is_empty()
+        );
+        assert_eq!(
+            SnapshotDiff {
+                added: vec![id(&current, 3)],
+                removed: Vec::new(),
+                changed: vec![id(&current, 1)],
+            },
+            current
+                .provider_collection()
+                .diff_against_snapshot(&snapshot)?
+        );
+
+        let default_only = FrozenProviderCollectionValue::testing_new("[DefaultInfo()]");
+        let mut removed = vec![id(&recorded, 1), id(&recorded, 2)];
+        removed.sort();
+        assert_eq!(
+            removed,
+            default_only
+                .provider_collection()
+                .diff_against_snapshot(&snapshot)?
+                .removed
+        );
+
+        assert!(
+            current
+                .provider_collection()
+                .diff_against_snapshot(b"not json")
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn provider_collection_with() -> SharedResult<()> {
+        let mut tester = provider_collection_tester()?;
+        tester.run_starlark_bzl_test(indoc!(
+            r#"
+            load("//provider:defs1.bzl", "FooInfo", "BarInfo")
+            load("//provider:defs2.bzl", "foo1", "bar1")
+            def test():
+                c = create_collection([DefaultInfo(), foo1])
+                added = c.with(bar1)
+                assert_eq("bar1", added[BarInfo].bar)
+                assert_eq("foo1", added[FooInfo].foo)
+                assert_eq(False, BarInfo in c)
+
+                overridden = c.with(FooInfo(foo="x"), override = True)
+                assert_eq("x", overridden[FooInfo].foo)
+                assert_eq("foo1", c[FooInfo].foo)
+            "#
+        ))?;
+
+        let mut tester = provider_collection_tester()?;
+        let conflicting = indoc!(
+            r#"
+            load("//provider:defs1.bzl", "FooInfo")
+            load("//provider:defs2.bzl", "foo1")
+            def test():
+                create_collection([DefaultInfo(), foo1]).with(FooInfo(foo="x"))
+            "#
+        );
+        expect_error(
+            tester.run_starlark_bzl_test(conflicting),
+            conflicting,
+            "provider of type FooInfo specified twice",
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn provider_collection_slice() -> SharedResult<()> {
+        let mut tester = provider_collection_tester()?;
+        tester.run_starlark_bzl_test(indoc!(
+            r#"
+            load("//provider:defs1.bzl", "FooInfo", "BarInfo", "BazInfo")
+            load("//provider:defs2.bzl", "foo1", "bar1", "baz1")
+            def assert_providers(c, expected):
+                for p in [DefaultInfo, FooInfo, BarInfo, BazInfo]:
+                    assert_eq(p in expected, p in c)
+
+            def test():
+                c = create_collection([foo1, DefaultInfo(), bar1, baz1])
+                assert_providers(c[2:3], [DefaultInfo, BarInfo])
+                assert_providers(c[:1], [DefaultInfo, FooInfo])
+                assert_providers(c[-1:], [DefaultInfo, BazInfo])
+                assert_providers(c[2:100], [DefaultInfo, BarInfo, BazInfo])
+                assert_providers(c[-100:1], [DefaultInfo, FooInfo])
+                assert_providers(c[5:10], [DefaultInfo])
+                assert_providers(c[:], [DefaultInfo, FooInfo, BarInfo, BazInfo])
+                assert_eq("bar1", c[2:3][BarInfo].bar)
+            "#
+        ))?;
+
+        let mut tester = provider_collection_tester()?;
+        let with_step = indoc!(
+            r#"
+            load("//provider:defs2.bzl", "foo1")
+            def test():
+                create_collection([foo1, DefaultInfo()])[::2]
+            "#
+        );
+        expect_error(
+            tester.run_starlark_bzl_test(with_step),
+            with_step,
+            "cannot have a step",
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn provider_factory() -> anyhow::Result<()> {
+        let globals = testing_globals();
+        let synthesized = {
+            let env = Module::new();
+            let value = coerce::testing::to_value(
+                &env,
+                &globals,
+                indoc!(
+                    r#"
+                    BazInfo = provider(fields=["baz"])
+                    BazInfo(baz="synthesized")
+                    "#
+                ),
+            );
+            env.set("", value);
+            env.freeze()?.get("")?
+        };
+        let baz_info = synthesized.value().as_provider().unwrap().id().dupe();
+
+        let env = Module::new();
+        let value = coerce::testing::to_value(
+            &env,
+            &globals,
+            indoc!(
+                r#"
+                BazInfo = provider(fields=["baz"])
+                QuxInfo = provider(fields=["qux"])
+                [BazInfo, QuxInfo, [DefaultInfo()]]
+                "#
+            ),
+        );
+        let value = ListRef::from_value(value).unwrap();
+        let (baz, qux) = (value[0], value[1]);
+        let collection = ProviderCollection::try_from_value(value[2])?;
+
+        let registered = synthesized.dupe();
+        register_provider_factory(&baz_info, Box::new(move || anyhow::Ok(registered.dupe())))?;
+        assert!(
+            collection.get(baz)?.is_none(),
+            "factories are disabled by default"
+        );
+
+        with_provider_factories_enabled(|| {
+            assert_eq!(
+                r#"BazInfo(baz="synthesized")"#,
+                collection.at(baz, env.heap())?.to_repr()
+            );
+            assert!(collection.get(qux)?.is_none());
+            anyhow::Ok(())
+        })?;
+        assert!(collection.get(baz)?.is_none());
+        assert!(
+            register_provider_factory(&baz_info, Box::new(move || anyhow::Ok(synthesized.dupe())))
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn all_field_names() {
+        let collection = FrozenProviderCollectionValue::testing_new(indoc!(
+            r#"
+            FooInfo = provider(fields=["a", "b"])
+            BarInfo = provider(fields=["b", "c"])
+            [DefaultInfo(), FooInfo(a=1, b=2), BarInfo(b=3)]
+            "#
+        ));
+        assert_eq!(
+            vec![
+                "a",
+                "b",
+                "c",
+                "default_outputs",
+                "other_outputs",
+                "sub_targets"
+            ],
+            collection
+                .provider_collection()
+                .all_field_names()
+                .into_iter()
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn one_line() {
+        let collection = FrozenProviderCollectionValue::testing_new(indoc!(
+            r#"
+            FooInfo = provider(fields=[])
+            BarInfo = provider(fields=[])
+            [DefaultInfo(), FooInfo(), BarInfo()]
+            "#
+        ));
+        assert_eq!(
+            "Providers{BarInfo, DefaultInfo, FooInfo}",
+            collection.provider_collection().one_line()
+        );
+
+        let defs: String = (0..10)
+            .map(|i| format!("Info{} = provider(fields=[])\n", i))
+            .collect();
+        let providers: Vec<String> = (0..10).map(|i| format!("Info{}()", i)).collect();
+        let collection = FrozenProviderCollectionValue::testing_new(&format!(
+            "{}[DefaultInfo(), {}]",
+            defs,
+            providers.join(", ")
+        ));
+        assert_eq!(
+            "Providers{DefaultInfo, Info0, Info1, Info2, Info3, Info4, Info5, Info6, +3 more}",
+            collection.provider_collection().one_line()
+        );
+    }
+
+    #[test]
+    fn strict_string_index() {
+        let heap = Heap::new();
+        let index = heap.alloc("FooInfo");
+
+        let lenient = index_not_a_provider(GetOp::At, index, false).to_string();
+        assert!(lenient.contains("must be a provider type"), "{}", lenient);
+
+        let strict = index_not_a_provider(GetOp::At, index, true).to_string();
+        assert_eq!(
+            "string indexing is disabled; use the provider callable FooInfo",
+            strict
+        );
+
+        // Only strings get the dedicated error.
+        let strict = index_not_a_provider(GetOp::At, heap.alloc(1), true).to_string();
+        assert!(strict.contains("must be a provider type"), "{}", strict);
+    }
+
+    #[test]
+    fn map_default_info() -> anyhow::Result<()> {
+        let value = FrozenProviderCollectionValue::testing_new(indoc!(
+            r#"
+            FooInfo = provider(fields=[])
+            [DefaultInfo(), FooInfo(), RunInfo(args=["a"])]
+            "#
+        ));
+        let collection = value.provider_collection();
+        let replacement = FrozenProviderCollectionValue::testing_new(
+            r#"[DefaultInfo(sub_targets = {"sub": [DefaultInfo()]})]"#,
+        );
+
+        let mapped = value.map_default_info(|default_info| {
+            assert!(default_info.sub_targets().is_empty());
+            Ok(replacement.owned(replacement.provider_collection().default_info_value()))
+        })?;
+        // The mapped collection keeps the heap of the new `DefaultInfo` alive.
+        drop(replacement);
+        let mapped = mapped.provider_collection();
+        assert_eq!(collection.provider_names(), mapped.provider_names());
+        assert_eq!(
+            vec!["sub"],
+            mapped
+                .default_info()
+                .sub_targets()
+                .keys()
+                .copied()
+                .collect::<Vec<_>>()
+        );
+        let foo_info = collection.provider_ids()[1];
+        assert_eq!(
+            collection.get_provider_raw(foo_info),
+            mapped.get_provider_raw(foo_info)
+        );
 
-            let res = collection
-                .unpack_frozen()
-                .expect("a frozen value")
-                .downcast_ref::<FrozenProviderCollection>()
-                .ok_or_else(|| {
-                    anyhow::anyhow!("{:?} was not a FrozenProviderCollection", collection)
-                })?
-                .contains_provider(&id);
+        let run_info = value.owned(
+            *collection
+                .get_provider_raw(RunInfoCallable::provider_id())
+                .unwrap(),
+        );
+        let err = value.map_default_info(|_| Ok(run_info.dupe())).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("should have been `DefaultInfo` was not"),
+            "{}",
+            err
+        );
+        Ok(())
+    }
 
-            Ok(res)
+    #[test]
+    fn merge_with() -> anyhow::Result<()> {
+        let left_value = collection_with_defs(r#"[DefaultInfo(), FooInfo(foo="left"), BarInfo()]"#);
+        let right_value = collection_with_defs(
+            r#"[DefaultInfo(sub_targets = {"sub": [DefaultInfo()]}), BazInfo(), FooInfo(foo="right")]"#,
+        );
+        let left = left_value.provider_collection();
+        let right = right_value.provider_collection();
+        let ids = left.provider_ids();
+        let (default_info, foo_info, bar_info) = (ids[0], ids[1], ids[2]);
+        let baz_info = right.provider_ids()[1];
+
+        let merged = left_value.merge_with(&right_value, |_, l, _| Ok(l))?;
+        let merged = merged.provider_collection();
+        assert_eq!(
+            vec!["DefaultInfo", "FooInfo", "BarInfo", "BazInfo"],
+            merged.provider_names()
+        );
+        for id in [default_info, foo_info, bar_info] {
+            assert_eq!(left.get_provider_raw(id), merged.get_provider_raw(id));
         }
+        assert_eq!(
+            right.get_provider_raw(baz_info),
+            merged.get_provider_raw(baz_info)
+        );
 
-        fn providers_list<'v>(collection: Value<'v>) -> anyhow::Result<Vec<String>> {
-            Ok(collection
-                .unpack_frozen()
-                .expect("a frozen value")
-                .downcast_ref::<FrozenProviderCollection>()
-                .ok_or_else(|| {
-                    anyhow::anyhow!("{:?} was not a FrozenProviderCollection", collection)
-                })?
-                .provider_names())
+        let merged = left_value.merge_with(&right_value, |_, _, r| Ok(r))?;
+        let merged = merged.provider_collection();
+        for id in [default_info, foo_info, baz_info] {
+            assert_eq!(right.get_provider_raw(id), merged.get_provider_raw(id));
         }
+        assert_eq!(
+            left.get_provider_raw(bar_info),
+            merged.get_provider_raw(bar_info)
+        );
+        assert_eq!(
+            vec!["sub"],
+            merged
+                .default_info()
+                .sub_targets()
+                .keys()
+                .copied()
+                .collect::<Vec<_>>()
+        );
+
+        let err = left_value
+            .merge_with(&right_value, |id, _, _| {
+                Err(anyhow::anyhow!("cannot merge `{}`", id))
+            })
+            .unwrap_err();
+        assert!(err.to_string().contains("cannot merge `"), "{}", err);
+
+        let bar_value = left_value.owned(*left.get_provider_raw(bar_info).unwrap());
+        let err = left_value
+            .merge_with(&right_value, |id, l, _| {
+                Ok(if id == foo_info { bar_value.dupe() } else { l })
+            })
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("merging conflicting `FooInfo` providers should produce a `FooInfo`"),
+            "{}",
+            err
+        );
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use buck2_common::result::SharedResult;
-    use buck2_core::bzl::ImportPath;
-    use buck2_interpreter_for_build::interpreter::testing::expect_error;
-    use buck2_interpreter_for_build::interpreter::testing::Tester;
-    use indoc::indoc;
+    #[test]
+    fn has_providers_but_no_outputs() {
+        let check = |providers: &str| {
+            collection_with_defs(providers)
+                .provider_collection()
+                .has_providers_but_no_outputs()
+        };
 
-    use crate::interpreter::build_defs::register_provider;
-    use crate::interpreter::rule_defs::artifact::testing::artifactory;
-    use crate::interpreter::rule_defs::provider::collection::tester::collection_creator;
-    use crate::interpreter::rule_defs::register_rule_defs;
+        assert!(check("[DefaultInfo(), FooInfo()]"));
+        assert!(check("[FooInfo()]"));
+        assert!(!check("[DefaultInfo()]"));
+        assert!(!check(r#"[DefaultInfo(), RunInfo(args=["a"])]"#));
+        assert!(!check(
+            r#"[DefaultInfo(sub_targets = {"sub": [DefaultInfo()]}), FooInfo()]"#
+        ));
+    }
 
-    fn provider_collection_tester() -> SharedResult<Tester> {
-        let mut tester = Tester::new()?;
-        tester.additional_globals(collection_creator);
-        tester.additional_globals(artifactory);
-        tester.additional_globals(register_rule_defs);
-        tester.additional_globals(register_provider);
-        tester.add_import(
-            &ImportPath::testing_new("root//provider:defs1.bzl"),
-            indoc!(
-                r#"
-                    FooInfo = provider(fields=["foo"])
-                    BarInfo = provider(fields=["bar"])
-                    BazInfo = provider(fields=["baz"])
-                    "#
-            ),
-        )?;
-        tester.add_import(
-            &ImportPath::testing_new("root//provider:defs2.bzl"),
-            indoc!(
-                r#"
-                    load("//provider:defs1.bzl", "FooInfo", "BarInfo", "BazInfo")
-                    foo1 = FooInfo(foo="foo1")
-                    foo2 = FooInfo(foo="foo2")
-                    bar1 = BarInfo(bar="bar1")
-                    baz1 = BazInfo(baz="baz1")
-                    "#
-            ),
-        )?;
+    #[test]
+    fn change_kind() -> anyhow::Result<()> {
+        let old = collection_with_defs(r#"[DefaultInfo(), FooInfo(foo="f1")]"#);
+        let old = old.provider_collection();
 
-        Ok(tester)
+        let kind = |new: &str| old.change_kind(collection_with_defs(new).provider_collection());
+        assert_eq!(
+            ChangeKind::Unchanged,
+            kind(r#"[FooInfo(foo="f1"), DefaultInfo()]"#)?
+        );
+        assert_eq!(
+            ChangeKind::OutputsChanged,
+            kind(r#"[DefaultInfo(sub_targets = {"sub": [DefaultInfo()]}), FooInfo(foo="f1")]"#)?
+        );
+        assert_eq!(
+            ChangeKind::ProvidersChanged,
+            kind(r#"[DefaultInfo(), FooInfo(foo="f2")]"#)?
+        );
+        assert_eq!(
+            ChangeKind::ProvidersChanged,
+            kind(r#"[DefaultInfo(), FooInfo(foo="f1"), BarInfo()]"#)?
+        );
+        assert_eq!(
+            ChangeKind::ProvidersChanged,
+            kind(r#"[DefaultInfo(sub_targets = {"sub": [DefaultInfo()]}), FooInfo(foo="f2")]"#)?
+        );
+        Ok(())
     }
 
     #[test]
-    fn provider_collection_constructs_properly() -> SharedResult<()> {
+    fn provider_collection_assert_providers_equal() -> SharedResult<()> {
         let mut tester = provider_collection_tester()?;
-        tester.run_starlark_bzl_test(indoc!(
+        let prelude = indoc!(
             r#"
-            load("//provider:defs1.bzl", "FooInfo", "BarInfo", "BazInfo")
-            load("//provider:defs2.bzl", "foo1", "bar1")
-            def test():
-                col = create_collection([foo1, bar1, DefaultInfo()])
-                assert_eq(None, col.get(BazInfo))
-                assert_eq("foo1", col[FooInfo].foo)
-                assert_eq("bar1", col[BarInfo].bar)
-                assert_eq([], col[DefaultInfo].default_outputs)
+            load("//provider:defs1.bzl", "FooInfo", "BarInfo")
+            c = create_collection([
+                DefaultInfo(sub_targets = {"sub": [FooInfo(foo="f")]}),
+                FooInfo(foo="f"),
+            ])
             "#
+        );
+        tester.run_starlark_bzl_test(&format!(
+            "{}{}",
+            prelude,
+            indoc!(
+                r#"
+                def test():
+                    default_info = c[DefaultInfo]
+                    assert_providers_equal(c, [FooInfo(foo="f"), default_info])
+                    assert_providers_equal(default_info.sub_targets["sub"], [FooInfo(foo="f")])
+                    # A list is never equal to a collection, whichever side it's on.
+                    assert_eq(False, c == [FooInfo(foo="f"), default_info])
+                    assert_eq(False, [FooInfo(foo="f"), default_info] == c)
+                "#
+            )
         ))?;
+
+        for expected in [
+            r#"[c[DefaultInfo], FooInfo(foo="g")]"#,
+            "[c[DefaultInfo]]",
+            r#"[c[DefaultInfo], FooInfo(foo="f"), BarInfo(bar="b")]"#,
+            r#"[c[DefaultInfo], FooInfo(foo="f"), FooInfo(foo="f")]"#,
+            r#"[FooInfo(foo="f")]"#,
+            r#"[c[DefaultInfo], FooInfo(foo="f"), 1]"#,
+        ] {
+            let content = format!(
+                "{}def test():\n    assert_providers_equal(c, {})\n",
+                prelude, expected
+            );
+            expect_error(
+                tester.run_starlark_bzl_test(&content),
+                &content,
+                "does not have the providers",
+            );
+        }
+        let content = format!(
+            "{}def test():\n    assert_providers_equal(c, \"c\")\n",
+            prelude
+        );
+        expect_error(
+            tester.run_starlark_bzl_test(&content),
+            &content,
+            "is not a list",
+        );
         Ok(())
     }
 
     #[test]
-    fn provider_collection_fails_to_construct_on_bad_data() -> SharedResult<()> {
-        let mut tester = provider_collection_tester()?;
-        let not_a_list = indoc!(
+    fn value_diff() -> anyhow::Result<()> {
+        let cached = collection_with_defs(
+            r#"[DefaultInfo(), FooInfo(foo="f1"), BarInfo(bar="b1"), BazInfo()]"#,
+        );
+        let fresh =
+            collection_with_defs(r#"[BarInfo(bar="b2"), FooInfo(foo="f1"), DefaultInfo()]"#);
+
+        let changed = cached
+            .provider_collection()
+            .value_diff(fresh.provider_collection())?;
+        assert_eq!(
+            vec!["BarInfo"],
+            changed
+                .iter()
+                .map(|id| id.name.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert!(
+            cached
+                .provider_collection()
+                .value_diff(cached.provider_collection())?
+                .is_empty()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn deserialized_provider_collection() -> anyhow::Result<()> {
+        let value = FrozenProviderCollectionValue::testing_new(indoc!(
             r#"
-            load("//provider:defs2.bzl", "foo1")
-            def test():
-                create_collection(foo1)
+            FooInfo = provider(fields=["foo"])
+            [FooInfo(foo="f1"), DefaultInfo(), RunInfo(args=["a"])]
             "#
+        ));
+        let collection = value.provider_collection();
+        let deserialized =
+            DeserializedProviderCollection::from_json(&collection.to_canonical_bytes()?)?;
+
+        let mut ids: Vec<&ProviderId> = collection.provider_ids();
+        ids.sort();
+        assert_eq!(
+            ids.iter().map(|id| id.name.as_str()).collect::<Vec<_>>(),
+            deserialized.provider_names()
         );
-        expect_error(
-            tester.run_starlark_bzl_test(not_a_list),
-            not_a_list,
-            "expected a list of Provider objects",
+        for id in ids {
+            assert_eq!(
+                Some(&serde_json::to_value(
+                    collection.get_provider_raw(id).unwrap()
+                )?),
+                deserialized.get(&id.name)
+            );
+        }
+        assert_eq!(
+            canonical_provider_id(collection.provider_ids()[0]),
+            deserialized.providers().last().unwrap().id
+        );
+        assert_eq!(None, deserialized.get("BarInfo"));
+
+        assert!(DeserializedProviderCollection::from_json(b"{}").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn missing_declared_subtargets() {
+        let value = FrozenProviderCollectionValue::testing_new(
+            r#"[DefaultInfo(sub_targets = {"headers": [DefaultInfo()], "objects": [DefaultInfo()]})]"#,
+        );
+        let collection = value.provider_collection();
+
+        assert_eq!(
+            vec!["docs".to_owned()],
+            collection.missing_declared_subtargets(&["headers", "docs", "objects"])
+        );
+        assert!(
+            collection
+                .missing_declared_subtargets(&["objects"])
+                .is_empty()
+        );
+        assert!(collection.missing_declared_subtargets(&[]).is_empty());
+    }
+
+    #[test]
+    fn tracing_provider_collection() {
+        let value = FrozenProviderCollectionValue::testing_new(indoc!(
+            r#"
+            FooInfo = provider(fields=[])
+            BarInfo = provider(fields=[])
+            [DefaultInfo(), FooInfo()]
+            "#
+        ));
+        let collection = value.provider_collection();
+        let foo_info = collection.provider_ids()[1].clone();
+        let bar_info = ProviderId {
+            name: "BarInfo".to_owned(),
+            ..foo_info.clone()
+        };
+
+        let accesses = Arc::new(Mutex::new(Vec::new()));
+        let tracing = TracingProviderCollection::new(collection, {
+            let accesses = accesses.dupe();
+            Box::new(move |op, id, hit| {
+                accesses.lock().unwrap().push(format!(
+                    "{} {} {}",
+                    op,
+                    id,
+                    if hit { "hit" } else { "miss" }
+                ));
+            })
+        });
+
+        assert!(tracing.contains_provider(&foo_info));
+        assert!(tracing.get_provider_raw(&bar_info).is_none());
+        assert!(tracing.at(&foo_info).is_ok());
+        assert!(tracing.at(&bar_info).is_err());
+        assert!(!tracing.collection().contains_provider(&bar_info));
+
+        assert_eq!(
+            vec![
+                "in FooInfo hit",
+                ".get BarInfo miss",
+                "[] FooInfo hit",
+                "[] BarInfo miss",
+            ],
+            *accesses.lock().unwrap()
         );
+    }
+
+    #[test]
+    fn provider_ordinal() {
+        let a = collection_with_defs("[DefaultInfo(), FooInfo()]");
+        let b = collection_with_defs("[BarInfo(), FooInfo(), DefaultInfo()]");
+        let a = a.provider_collection().provider_ids();
+        let b = b.provider_collection().provider_ids();
+
+        assert_eq!(a[1].ordinal(), b[1].ordinal());
+        assert_eq!(a[0].ordinal(), b[2].ordinal());
+        assert_ne!(a[1].ordinal(), b[0].ordinal());
+        assert_ne!(a[0].ordinal(), a[1].ordinal());
+    }
 
+    #[test]
+    fn assert_outputs_unique() -> SharedResult<()> {
         let mut tester = provider_collection_tester()?;
-        let not_a_provider = indoc!(
+        tester.run_starlark_bzl_test(indoc!(
             r#"
-            load("//provider:defs2.bzl", "foo1", "bar1")
+            a = source_artifact("foo", "a.cpp")
+            b = source_artifact("foo", "b.cpp")
+            c = create_collection([DefaultInfo(
+                default_outputs = [a],
+                sub_targets = {
+                    "b": [DefaultInfo(default_outputs = [b])],
+                    "nested": [DefaultInfo(sub_targets = {"empty": [DefaultInfo()]})],
+                },
+            )])
             def test():
-                create_collection([foo1, bar1, "not a provider", DefaultInfo()])
+                assert_outputs_unique(c)
             "#
-        );
-        expect_error(
-            tester.run_starlark_bzl_test(not_a_provider),
-            not_a_provider,
-            "expected a Provider object",
-        );
+        ))?;
 
         let mut tester = provider_collection_tester()?;
-        let duplicate_provider_types = indoc!(
+        let duplicate = indoc!(
             r#"
-            load("//provider:defs2.bzl", "foo1", "foo2", "bar1")
+            a = source_artifact("foo", "a.cpp")
+            b = source_artifact("foo", "b.cpp")
+            c = create_collection([DefaultInfo(
+                default_outputs = [a],
+                sub_targets = {
+                    "b": [DefaultInfo(default_outputs = [b])],
+                    "nested": [DefaultInfo(sub_targets = {"again": [DefaultInfo(default_outputs = [b])]})],
+                },
+            )])
             def test():
-                create_collection([foo1, bar1, foo2, DefaultInfo()])
+                assert_outputs_unique(c)
             "#
         );
         expect_error(
-            tester.run_starlark_bzl_test(duplicate_provider_types),
-            duplicate_provider_types,
-            "specified twice",
+            tester.run_starlark_bzl_test(duplicate),
+            duplicate,
+            "is a default output of both sub-target `[b]` and sub-target `[nested][again]`",
         );
 
         let mut tester = provider_collection_tester()?;
-        let missing_default_info = indoc!(
+        let duplicate_at_root = indoc!(
             r#"
-            load("//provider:defs2.bzl", "foo1", "bar1")
+            a = source_artifact("foo", "a.cpp")
+            c = create_collection([DefaultInfo(
+                default_outputs = [a],
+                sub_targets = {"a": [DefaultInfo(default_outputs = [a])]},
+            )])
             def test():
-                create_collection([foo1, bar1])
+                assert_outputs_unique(c)
             "#
         );
         expect_error(
-            tester.run_starlark_bzl_test(missing_default_info),
-            missing_default_info,
-            "did not receive a DefaultInfo",
+            tester.run_starlark_bzl_test(duplicate_at_root),
+            duplicate_at_root,
+            "is a default output of both the collection and sub-target `[a]`",
         );
         Ok(())
     }
 
     #[test]
-    fn returns_default_info() -> SharedResult<()> {
+    fn provider_collection_get_or() -> SharedResult<()> {
         let mut tester = provider_collection_tester()?;
         tester.run_starlark_bzl_test(indoc!(
             r#"
-            artifact = source_artifact("foo", "bar.cpp")
-            frozen_collection = create_collection([
-                DefaultInfo(
-                    sub_targets={"foo": []},
-                    default_outputs=[artifact]
-                )
-            ])
+            load("//provider:defs1.bzl", "FooInfo", "BarInfo")
+            c = create_collection([DefaultInfo(), FooInfo(foo="f")])
             def test():
-                di_sub_targets = get_default_info_sub_targets(frozen_collection)
-                di_default_outputs = get_default_info_default_outputs(frozen_collection)
-                assert_eq([], di_sub_targets["foo"][DefaultInfo].default_outputs)
-                assert_eq([artifact], di_default_outputs)
+                calls = []
+                def fallback():
+                    calls.append(1)
+                    return BarInfo(bar="fallback")
+
+                assert_eq("f", c.get_or(FooInfo, fallback).foo)
+                assert_eq(0, len(calls))
+                assert_eq("fallback", c.get_or(BarInfo, fallback).bar)
+                assert_eq(1, len(calls))
+                assert_eq("x", c.get_or(BarInfo, lambda: "x"))
             "#
         ))
     }
 
     #[test]
-    fn provider_collection_contains_methods_and_in_operator() -> SharedResult<()> {
-        let mut tester = provider_collection_tester()?;
-        tester.add_import(
-            &ImportPath::testing_new("root//providers:defs.bzl"),
-            indoc!(
-                r#"
-                FooInfo = provider(fields=["foo"])
-                BarInfo = provider(fields=["bar"])
-                "#
-            ),
-        )?;
-        tester.run_starlark_bzl_test(indoc!(
+    fn internal_providers_are_not_serialized_for_audit() -> anyhow::Result<()> {
+        let value = FrozenProviderCollectionValue::testing_new(indoc!(
             r#"
-            load("//providers:defs.bzl", "FooInfo", "BarInfo")
-            c1 = create_collection([DefaultInfo(), FooInfo(foo="f1")])
-            c2 = create_collection([DefaultInfo(), FooInfo(foo="f2"), BarInfo(bar="b2")])
-            def test():
-                assert_eq(True, contains_provider(c1, FooInfo))
-                assert_eq(True, FooInfo in c1)
-                assert_eq(False, contains_provider(c1, BarInfo))
-                assert_eq(False, BarInfo in c1)
-                assert_eq(["DefaultInfo", "FooInfo", "BarInfo"], providers_list(c2))
+            ImplementationDetailInfo = provider(fields=[])
+            PublicInfo = provider(fields=[])
+            [DefaultInfo(), ImplementationDetailInfo(), PublicInfo()]
             "#
-        ))
+        ));
+        let collection = value.provider_collection();
+        let internal = collection.provider_ids()[1];
+        register_internal_provider(internal);
+        let names = |value: serde_json::Value| -> Vec<String> {
+            value.as_object().unwrap().keys().cloned().collect()
+        };
+
+        assert_eq!(
+            vec!["DefaultInfo", "PublicInfo"],
+            names(serde_json::to_value(AuditProviderCollection(collection))?)
+        );
+        assert_eq!(
+            vec!["DefaultInfo", "ImplementationDetailInfo", "PublicInfo"],
+            names(with_internal_providers_included(|| serde_json::to_value(
+                AuditProviderCollection(collection)
+            ))?)
+        );
+        assert_eq!(
+            vec!["DefaultInfo", "PublicInfo"],
+            names(serde_json::to_value(AuditProviderCollection(collection))?)
+        );
+        assert_eq!(
+            vec!["DefaultInfo", "ImplementationDetailInfo", "PublicInfo"],
+            names(serde_json::to_value(collection)?)
+        );
+        assert!(collection.contains_provider(internal));
+        Ok(())
     }
 
     #[test]
-    fn provider_collection_get() -> SharedResult<()> {
-        let mut tester = provider_collection_tester()?;
-        tester.add_import(
-            &ImportPath::testing_new("root//providers:defs.bzl"),
-            indoc!(
-                r#"
-                FooInfo = provider(fields=["foo"])
-                BarInfo = provider(fields=["bar"])
+    fn canonical_bytes_include_internal_providers_in_sub_targets() -> anyhow::Result<()> {
+        let collection = |value: &str| {
+            FrozenProviderCollectionValue::testing_new(&format!(
+                indoc!(
+                    r#"
+                    NestedImplementationDetailInfo = provider(fields=["value"])
+                    [DefaultInfo(sub_targets = {{
+                        "sub": [DefaultInfo(), NestedImplementationDetailInfo(value = "{}")],
+                    }})]
+                    "#
+                ),
+                value
+            ))
+        };
+        let before = collection("a");
+        let after = collection("b");
+        let sub = before
+            .provider_collection()
+            .default_info()
+            .get_sub_target_providers("sub")
+            .unwrap();
+        register_internal_provider(sub.provider_ids()[1]);
 
-                # Frozen collection
-                cf = create_collection([DefaultInfo(), FooInfo(foo="f1")])
-                "#
-            ),
-        )?;
-        tester.run_starlark_bzl_test(indoc!(
+        assert_ne!(
+            before.provider_collection().to_canonical_bytes()?,
+            after.provider_collection().to_canonical_bytes()?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn locate_provider() {
+        let value = FrozenProviderCollectionValue::testing_new(indoc!(
             r#"
-            load("//providers:defs.bzl", "FooInfo", "BarInfo", "cf")
+            FooInfo = provider(fields=[])
+            BarInfo = provider(fields=[])
+            [
+                DefaultInfo(sub_targets = {
+                    "a": [DefaultInfo(), FooInfo()],
+                    "b": [DefaultInfo(sub_targets = {"nested": [DefaultInfo(), FooInfo()]})],
+                }),
+                BarInfo(),
+            ]
+            "#
+        ));
+        let collection = value.provider_collection();
+        let bar_info = collection.provider_ids()[1];
+        let foo_info = ProviderId {
+            name: "FooInfo".to_owned(),
+            ..bar_info.clone()
+        };
+        let locate = |id: &ProviderId| -> Vec<String> {
+            collection
+                .locate_provider(id)
+                .iter()
+                .map(|name| name.to_string())
+                .collect()
+        };
 
-            # Unfrozen collection
-            cu = create_collection([DefaultInfo(), FooInfo(foo="f1")])
+        assert_eq!(vec!["[a]", "[b][nested]"], locate(&foo_info));
+        assert_eq!(
+            vec![ProvidersName::Default],
+            collection.locate_provider(bar_info)
+        );
+        assert_eq!(
+            Vec::<String>::new(),
+            locate(&ProviderId {
+                name: "NeverDefinedInfo".to_owned(),
+                ..bar_info.clone()
+            })
+        );
+    }
 
-            def test():
-                assert_eq("f1", cu.get(FooInfo).foo)
-                assert_eq(None, cu.get(BarInfo))
-                assert_eq("f1", cf.get(FooInfo).foo)
-                assert_eq(None, cf.get(BarInfo))
+    #[test]
+    fn serialize_delta() -> anyhow::Result<()> {
+        let base = collection_with_defs(r#"[DefaultInfo(), FooInfo(foo="f1"), BarInfo(bar="b1")]"#);
+        let other = collection_with_defs(r#"[DefaultInfo(), FooInfo(foo="f2"), BazInfo()]"#);
+        let base = base.provider_collection();
+        let other = other.provider_collection();
+        let id = |collection: &FrozenProviderCollection, i: usize| {
+            canonical_provider_id(collection.provider_ids()[i])
+        };
+
+        let delta = other.serialize_delta(base)?;
+        let mut changed = vec![id(other, 1), id(other, 2)];
+        changed.sort();
+        assert_eq!(
+            changed,
+            delta
+                .changed
+                .iter()
+                .map(|(id, _)| id.clone())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(vec![id(base, 2)], delta.removed);
+
+        let delta: DeltaEncoded = serde_json::from_str(&serde_json::to_string(&delta)?)?;
+        assert_eq!(
+            DeserializedProviderCollection::from_json(&other.to_canonical_bytes()?)?,
+            base.apply_delta(&delta)?
+        );
+
+        let err = other.apply_delta(&delta).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("provider collection delta applies to a base with fingerprint"),
+            "{}",
+            err
+        );
+
+        assert!(base.serialize_delta(base)?.changed.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn change_report() {
+        let before = collection_with_defs(indoc!(
+            r#"
+            [
+                DefaultInfo(sub_targets = {
+                    "kept": [DefaultInfo()],
+                    "changed": [DefaultInfo(), FooInfo(foo="f1")],
+                    "removed": [DefaultInfo()],
+                }),
+                FooInfo(foo="f1"),
+                BarInfo(),
+            ]
             "#
-        ))
+        ));
+        let after = collection_with_defs(indoc!(
+            r#"
+            [
+                DefaultInfo(sub_targets = {
+                    "added": [DefaultInfo()],
+                    "changed": [DefaultInfo(), FooInfo(foo="f2")],
+                    "kept": [DefaultInfo()],
+                }),
+                BazInfo(),
+                FooInfo(foo="f2"),
+            ]
+            "#
+        ));
+
+        assert_eq!(
+            indoc!(
+                r#"
+                Added providers:
+                  BazInfo
+                Removed providers:
+                  BarInfo
+                Changed fields:
+                  FooInfo.foo: "f1" -> "f2"
+                Sub-targets:
+                  + [added]
+                  ~ [changed]
+                  - [removed]
+                "#
+            ),
+            before
+                .provider_collection()
+                .change_report(after.provider_collection())
+        );
+        assert_eq!(
+            "",
+            before
+                .provider_collection()
+                .change_report(before.provider_collection())
+        );
     }
 }
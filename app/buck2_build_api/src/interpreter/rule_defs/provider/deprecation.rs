@@ -0,0 +1,61 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::Display;
+use std::sync::RwLock;
+
+use buck2_core::provider::id::InternedProviderId;
+use buck2_core::provider::id::ProviderId;
+use once_cell::sync::Lazy;
+
+/// Why a provider is deprecated, and what to use instead. Constructing a provider collection
+/// containing a deprecated provider reports a soft error with this information.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProviderDeprecation {
+    pub reason: Option<String>,
+    pub replacement: Option<String>,
+}
+
+static DEPRECATED_PROVIDERS: Lazy<RwLock<HashMap<InternedProviderId, ProviderDeprecation>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// Mark `id` as deprecated. Registering a provider again replaces its deprecation.
+pub fn register_deprecated_provider(id: &ProviderId, deprecation: ProviderDeprecation) {
+    DEPRECATED_PROVIDERS
+        .write()
+        .unwrap()
+        .insert(InternedProviderId::new(id), deprecation);
+}
+
+/// The deprecation registered for `id`, if any.
+pub fn provider_deprecation(id: InternedProviderId) -> Option<ProviderDeprecation> {
+    DEPRECATED_PROVIDERS.read().unwrap().get(&id).cloned()
+}
+
+/// Displays as a message like "`FooInfo` is deprecated: <reason>, use `BarInfo` instead".
+pub(crate) struct DeprecationMessage<'a> {
+    pub(crate) name: &'a str,
+    pub(crate) deprecation: &'a ProviderDeprecation,
+}
+
+impl<'a> Display for DeprecationMessage<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "`{}` is deprecated", self.name)?;
+        match (&self.deprecation.reason, &self.deprecation.replacement) {
+            (Some(reason), Some(replacement)) => {
+                write!(f, ": {}, use `{}` instead", reason, replacement)
+            }
+            (Some(reason), None) => write!(f, ": {}", reason),
+            (None, Some(replacement)) => write!(f, ": use `{}` instead", replacement),
+            (None, None) => Ok(()),
+        }
+    }
+}
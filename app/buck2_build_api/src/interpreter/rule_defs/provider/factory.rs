@@ -0,0 +1,110 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+use std::collections::HashMap;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::RwLock;
+
+use buck2_core::provider::id::InternedProviderId;
+use buck2_core::provider::id::ProviderId;
+use once_cell::sync::Lazy;
+use once_cell::sync::OnceCell;
+use starlark::values::FrozenValue;
+use starlark::values::OwnedFrozenValue;
+
+use crate::interpreter::rule_defs::provider::ValueAsProviderLike;
+
+/// Creates a provider on demand when it's looked up in a collection that doesn't have it, see
+/// `register_provider_factory`.
+pub type ProviderFactory = Box<dyn Fn() -> anyhow::Result<OwnedFrozenValue> + Send + Sync>;
+
+#[derive(Debug, thiserror::Error)]
+enum ProviderFactoryError {
+    #[error("a factory for provider `{0}` is already registered")]
+    AlreadyRegistered(String),
+    #[error("the factory for provider `{expected}` returned `{repr}`")]
+    WrongProvider { expected: String, repr: String },
+}
+
+struct RegisteredFactory {
+    factory: ProviderFactory,
+    value: OnceCell<OwnedFrozenValue>,
+}
+
+static FACTORIES_ENABLED: AtomicBool = AtomicBool::new(false);
+
+static FACTORIES: Lazy<RwLock<HashMap<InternedProviderId, Arc<RegisteredFactory>>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// Provider factories are only consulted once enabled. They are disabled by default.
+pub fn set_provider_factories_enabled(enabled: bool) {
+    FACTORIES_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Run `f` with provider factories enabled, then restore whether they were enabled before.
+/// Whether factories are enabled is process-wide rather than scoped to `f`: other threads see
+/// factories enabled while `f` runs, and if calls on several threads overlap, the one finishing
+/// last restores what it saw on entry, which may be another call's `true`.
+pub fn with_provider_factories_enabled<R>(f: impl FnOnce() -> R) -> R {
+    struct Restore(bool);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            FACTORIES_ENABLED.store(self.0, Ordering::Relaxed);
+        }
+    }
+
+    let _restore = Restore(FACTORIES_ENABLED.swap(true, Ordering::Relaxed));
+    f()
+}
+
+/// Register `factory` to create the provider `id` when a lookup in a collection misses it.
+/// The factory runs at most once, on the first such lookup, and its result is shared by every
+/// later one. Factories can't be replaced, so registering one for `id` again is an error.
+pub fn register_provider_factory(id: &ProviderId, factory: ProviderFactory) -> anyhow::Result<()> {
+    let mut factories = FACTORIES.write().unwrap();
+    let id = InternedProviderId::new(id);
+    if factories.contains_key(&id) {
+        return Err(ProviderFactoryError::AlreadyRegistered(id.name.clone()).into());
+    }
+    factories.insert(
+        id,
+        Arc::new(RegisteredFactory {
+            factory,
+            value: OnceCell::new(),
+        }),
+    );
+    Ok(())
+}
+
+/// The provider created by the factory for `id`, if factories are enabled and one is registered.
+pub(crate) fn synthesize_provider(id: InternedProviderId) -> anyhow::Result<Option<FrozenValue>> {
+    if !FACTORIES_ENABLED.load(Ordering::Relaxed) {
+        return Ok(None);
+    }
+    let registered = match FACTORIES.read().unwrap().get(&id).cloned() {
+        Some(registered) => registered,
+        None => return Ok(None),
+    };
+    let value = registered.value.get_or_try_init(|| {
+        let value = (registered.factory)()?;
+        match value.value().as_provider() {
+            Some(provider) if **provider.id() == *id => Ok(value),
+            _ => Err(ProviderFactoryError::WrongProvider {
+                expected: id.name.clone(),
+                repr: value.value().to_repr(),
+            }),
+        }
+    })?;
+    // Registered factories are never dropped, so the heap of `value` outlives any use of the
+    // returned value.
+    Ok(Some(unsafe { value.unchecked_frozen_value() }))
+}
@@ -0,0 +1,82 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+use std::cell::Cell;
+use std::collections::HashSet;
+use std::sync::RwLock;
+
+use buck2_core::provider::id::InternedProviderId;
+use buck2_core::provider::id::ProviderId;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use serde::Serializer;
+
+use crate::interpreter::rule_defs::provider::collection::FrozenProviderCollection;
+
+static INTERNAL_PROVIDERS: Lazy<RwLock<HashSet<InternedProviderId>>> =
+    Lazy::new(|| RwLock::new(HashSet::new()));
+
+thread_local! {
+    static INCLUDE_INTERNAL: Cell<bool> = Cell::new(false);
+}
+
+/// Mark `id` as internal, usually at startup. Internal providers carry implementation details,
+/// so `AuditProviderCollection` skips them unless serialized within
+/// `with_internal_providers_included`. Collections still contain them for lookups.
+pub fn register_internal_provider(id: &ProviderId) {
+    INTERNAL_PROVIDERS
+        .write()
+        .unwrap()
+        .insert(InternedProviderId::new(id));
+}
+
+pub fn is_internal_provider(id: InternedProviderId) -> bool {
+    INTERNAL_PROVIDERS.read().unwrap().contains(&id)
+}
+
+/// Run `f` with `AuditProviderCollection`s serialized on this thread including their internal
+/// providers, e.g. for `--include-internal`.
+pub fn with_internal_providers_included<R>(f: impl FnOnce() -> R) -> R {
+    struct Restore(bool);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            INCLUDE_INTERNAL.with(|include| include.set(self.0));
+        }
+    }
+
+    let _restore = Restore(INCLUDE_INTERNAL.with(|include| include.replace(true)));
+    f()
+}
+
+/// Whether serializing `id` for `buck2 audit` on this thread should skip it.
+fn skip_when_serializing(id: InternedProviderId) -> bool {
+    !INCLUDE_INTERNAL.with(|include| include.get()) && is_internal_provider(id)
+}
+
+/// Serializes a collection for `buck2 audit`, skipping its internal providers. The collection's
+/// own `Serialize` always includes every provider, since fingerprints and diffs are built from it.
+/// Only the top level is filtered: sub-target collections nested in `DefaultInfo` are serialized
+/// in full.
+pub struct AuditProviderCollection<'a>(pub &'a FrozenProviderCollection);
+
+impl<'a> Serialize for AuditProviderCollection<'a> {
+    fn serialize<S>(&self, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        s.collect_map(
+            self.0
+                .providers
+                .iter()
+                .filter(|(id, _)| !skip_when_serializing(**id))
+                .map(|(id, v)| (id.name(), v)),
+        )
+    }
+}
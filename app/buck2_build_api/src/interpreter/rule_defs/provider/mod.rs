@@ -57,6 +57,7 @@
 use std::fmt::Debug;
 use std::sync::Arc;
 
+use allocative::Allocative;
 use buck2_core::provider::id::ProviderId;
 use starlark::any::ProvidesStaticType;
 use starlark::environment::MethodsBuilder;
@@ -72,11 +73,16 @@ pub mod builtin;
 pub mod callable;
 pub mod collection;
 pub(crate) mod dependency;
+pub mod deprecation;
+pub mod factory;
+pub mod internal;
+pub mod protocol;
 pub mod registration;
+pub mod schema;
 pub mod test_provider;
 pub(crate) mod user;
 
-pub(crate) trait ProviderLike<'v>: Debug {
+pub(crate) trait ProviderLike<'v>: Debug + Allocative {
     /// The ID. Guaranteed to be set on the `ProviderCallable` before constructing this object
     fn id(&self) -> &Arc<ProviderId>;
     /// Gets the value for a given field.
@@ -0,0 +1,41 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use buck2_core::provider::id::ProviderId;
+use once_cell::sync::Lazy;
+
+/// A named set of providers that collections of some kind of target are expected to have, e.g.
+/// a "library" protocol requiring the providers needed to link against it. Check a collection
+/// with `FrozenProviderCollection::conforms_to`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProviderProtocol {
+    pub name: String,
+    pub required: Vec<Arc<ProviderId>>,
+}
+
+static PROVIDER_PROTOCOLS: Lazy<Mutex<HashMap<String, Arc<ProviderProtocol>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Make `protocol` available by name to tooling, usually at startup. Registering a protocol
+/// with the same name again replaces it.
+pub fn register_provider_protocol(protocol: ProviderProtocol) {
+    PROVIDER_PROTOCOLS
+        .lock()
+        .unwrap()
+        .insert(protocol.name.clone(), Arc::new(protocol));
+}
+
+/// The protocol registered as `name`, if any.
+pub fn lookup_provider_protocol(name: &str) -> Option<Arc<ProviderProtocol>> {
+    PROVIDER_PROTOCOLS.lock().unwrap().get(name).cloned()
+}
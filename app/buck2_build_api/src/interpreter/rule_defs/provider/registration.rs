@@ -7,10 +7,19 @@
  * of this source tree.
  */
 
+use std::collections::HashMap;
+
+use buck2_core::provider::id::InternedProviderId;
+use buck2_interpreter_for_build::provider::callable::ValueAsProviderCallableLike;
+use once_cell::sync::Lazy;
 use starlark::environment::GlobalsBuilder;
+use starlark::values::FrozenHeap;
+use starlark::values::FrozenHeapRef;
+use starlark::values::FrozenValue;
 
 pub(crate) struct ProviderRegistration {
     pub(crate) register_globals: fn(&mut GlobalsBuilder),
+    pub(crate) alloc_callable: fn(&FrozenHeap) -> FrozenValue,
 }
 
 inventory::collect!(ProviderRegistration);
@@ -20,3 +29,33 @@ pub fn register_builtin_providers(registry: &mut GlobalsBuilder) {
         (registration.register_globals)(registry);
     }
 }
+
+struct BuiltinProviderCallables {
+    // Keeps the callables alive.
+    _heap: FrozenHeapRef,
+    callables: HashMap<InternedProviderId, FrozenValue>,
+}
+
+static BUILTIN_PROVIDER_CALLABLES: Lazy<BuiltinProviderCallables> = Lazy::new(|| {
+    let heap = FrozenHeap::new();
+    let mut callables = HashMap::new();
+    for registration in inventory::iter::<ProviderRegistration> {
+        let callable = (registration.alloc_callable)(&heap);
+        if let Some(id) = callable
+            .to_value()
+            .as_provider_callable()
+            .and_then(|c| c.id())
+        {
+            callables.insert(InternedProviderId::new(id), callable);
+        }
+    }
+    BuiltinProviderCallables {
+        _heap: heap.into_ref(),
+        callables,
+    }
+});
+
+/// The callable of the builtin provider `id`, or `None` if `id` is not a builtin provider.
+pub(crate) fn builtin_provider_callable(id: InternedProviderId) -> Option<FrozenValue> {
+    BUILTIN_PROVIDER_CALLABLES.callables.get(&id).copied()
+}
@@ -0,0 +1,47 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+use serde::Deserialize;
+
+/// The providers a target is expected to return, and the fields they are expected to have, to
+/// pin a target's provider contract. Check a collection with
+/// `FrozenProviderCollection::validate_against_schema`.
+///
+/// Written in JSON like:
+/// ```json
+/// {"providers": [{"name": "FooInfo", "fields": [{"name": "foo", "type": "string"}]}]}
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ProviderSchema {
+    pub providers: Vec<ProviderSchemaEntry>,
+}
+
+/// A provider required by a `ProviderSchema`. Providers are matched by name.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ProviderSchemaEntry {
+    pub name: String,
+    #[serde(default)]
+    pub fields: Vec<FieldSchema>,
+}
+
+/// A field required on a provider of a `ProviderSchema`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct FieldSchema {
+    pub name: String,
+    /// The Starlark type of the field's value, e.g. `string` or `list`. Any type is accepted if
+    /// unset.
+    #[serde(rename = "type", default)]
+    pub ty: Option<String>,
+}
+
+impl ProviderSchema {
+    pub fn from_json(json: &str) -> anyhow::Result<ProviderSchema> {
+        Ok(serde_json::from_str(json)?)
+    }
+}
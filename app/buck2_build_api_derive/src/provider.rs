@@ -367,7 +367,7 @@ impl ProviderCodegen {
         Ok(quote! {
             impl<'v, V: starlark::values::ValueLike<'v> + 'v> crate::interpreter::rule_defs::provider::ProviderLike<'v> for #gen_name<V>
             where
-                Self: std::fmt::Debug,
+                Self: std::fmt::Debug + allocative::Allocative,
             {
                 fn id(&self) -> &std::sync::Arc<buck2_core::provider::id::ProviderId> {
                     #callable_name::provider_id()
@@ -451,6 +451,10 @@ impl ProviderCodegen {
                 fn id(&self) -> Option<&std::sync::Arc<buck2_core::provider::id::ProviderId>> {
                     Some(self.id)
                 }
+
+                fn interned_id(&self) -> Option<buck2_core::provider::id::InternedProviderId> {
+                    Some(Self::provider_id_t().interned())
+                }
             }
         })
     }
@@ -552,12 +556,16 @@ impl ProviderCodegen {
     }
 
     fn inventory(&self) -> syn::Result<proc_macro2::TokenStream> {
+        let callable_name = self.callable_name()?;
         Ok(quote! {
             inventory::submit! {
                 crate::interpreter::rule_defs::provider::registration::ProviderRegistration {
                     register_globals: |globals| {
                         register_provider(globals)
-                    }
+                    },
+                    alloc_callable: |heap| {
+                        heap.alloc(#callable_name::new())
+                    },
                 }
             }
         })
@@ -0,0 +1,52 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under both the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree and the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree.
+ */
+
+//! Looking up a provider in a collection, as `c[FooInfo]` does, either by interning the id of
+//! `FooInfo` on every lookup or with the interned id carried by `FooInfo`.
+
+use buck2_core::provider::id::InternedProviderId;
+use buck2_core::provider::id::ProviderId;
+use criterion::black_box;
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::Criterion;
+use starlark_map::small_map::SmallMap;
+
+fn provider_id(i: usize) -> ProviderId {
+    ProviderId {
+        path: None,
+        name: format!("Provider{}Info", i),
+    }
+}
+
+fn lookup(c: &mut Criterion) {
+    // Many providers are interned in a real build, not only the ones of this collection.
+    for i in 0..10000 {
+        InternedProviderId::new(&provider_id(i));
+    }
+    let collection: SmallMap<InternedProviderId, usize> = (0..10)
+        .map(|i| (InternedProviderId::new(&provider_id(i)), i))
+        .collect();
+    let id = provider_id(5);
+    let interned = InternedProviderId::new(&id);
+
+    let mut group = c.benchmark_group("provider_collection_lookup");
+    group.bench_function("interning_on_lookup", |b| {
+        b.iter(|| {
+            InternedProviderId::get(black_box(&id)).and_then(|id| collection.get(&id).copied())
+        })
+    });
+    group.bench_function("carried_interned_id", |b| {
+        b.iter(|| collection.get(black_box(&interned)).copied())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, lookup);
+criterion_main!(benches);
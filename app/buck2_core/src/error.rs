@@ -7,25 +7,264 @@
  * of this source tree.
  */
 
+use std::backtrace::Backtrace;
+use std::cell::Cell;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
 use std::str::FromStr;
+use std::sync::atomic::AtomicBool;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering;
+use std::sync::Arc;
 use std::sync::Mutex;
 
+use dupe::Dupe;
+use once_cell::sync::Lazy;
 use once_cell::sync::OnceCell;
+use pin_project::pin_project;
 use starlark_map::small_set::SmallSet;
 
 use crate::env_helper::EnvHelper;
 
 type SoftErrorHandler = Box<
-    dyn Fn(&'static str, &anyhow::Error, (&'static str, u32, u32), bool) + Send + Sync + 'static,
+    dyn Fn(
+            &'static str,
+            &anyhow::Error,
+            (&'static str, u32, u32),
+            bool,
+            Option<&SoftErrorAnnotation>,
+            Severity,
+            Option<&Backtrace>,
+        ) + Send
+        + Sync
+        + 'static,
 >;
 
 static HANDLER: OnceCell<SoftErrorHandler> = OnceCell::new();
 
 static HARD_ERROR: EnvHelper<HardErrorConfig> = EnvHelper::new("BUCK2_HARD_ERROR");
 
-static ALL_SOFT_ERROR_COUNTERS: Mutex<Vec<&'static AtomicUsize>> = Mutex::new(Vec::new());
+/// Capturing a backtrace for every soft error is expensive, so it's only done when debugging,
+/// with `$BUCK2_SOFT_ERROR_BACKTRACE=true`.
+static BACKTRACE: EnvHelper<bool> = EnvHelper::new("BUCK2_SOFT_ERROR_BACKTRACE");
+
+/// Whether soft errors capture a backtrace, read from `$BUCK2_SOFT_ERROR_BACKTRACE` by
+/// `initialize`.
+static CAPTURE_BACKTRACE: AtomicBool = AtomicBool::new(false);
+
+/// Counter of every `soft_error!` call site which has fired, with the category it reports.
+static ALL_SOFT_ERROR_COUNTERS: Mutex<Vec<(&'static str, &'static AtomicUsize)>> =
+    Mutex::new(Vec::new());
+
+/// How many of the errors counted by each call site's counter, keyed by its address, fired
+/// within a `SoftErrorVerboseScope`, so that they don't use up the limit of the call site.
+static VERBOSE_SOFT_ERROR_COUNTS: Lazy<Mutex<HashMap<usize, usize>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Hard error configs replacing `$BUCK2_HARD_ERROR` for soft errors reported while analyzing
+/// specific targets, see `set_target_hard_error_override`.
+static TARGET_HARD_ERROR_OVERRIDES: Lazy<Mutex<HashMap<String, Arc<HardErrorConfig>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+thread_local! {
+    /// The target soft errors reported on this thread are attributed to, see
+    /// `with_soft_error_target`.
+    static CURRENT_TARGET: RefCell<Option<String>> = RefCell::new(None);
+}
+
+static SOFT_ERROR_ANNOTATIONS: Lazy<Mutex<HashMap<&'static str, SoftErrorAnnotation>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// How many times each call site passes a soft error to the handler, unless overridden for
+/// its category.
+const DEFAULT_SOFT_ERROR_LIMIT: usize = 10;
+
+static SOFT_ERROR_LIMITS: Lazy<Mutex<HashMap<&'static str, usize>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// How many times a soft error call site passes errors to the handler before further errors
+/// are only counted, as returned by `effective_soft_error_limit`.
+#[derive(Debug, Clone, Copy, Dupe, PartialEq, Eq)]
+pub enum SoftErrorLimit {
+    /// No limit was set for the category, so the default one applies.
+    Default(usize),
+    /// The limit set for the category with `set_soft_error_limit`.
+    Overridden(usize),
+    /// There is no limit within a `SoftErrorVerboseScope`.
+    Unlimited,
+}
+
+impl SoftErrorLimit {
+    pub fn limit(self) -> usize {
+        match self {
+            SoftErrorLimit::Default(limit) | SoftErrorLimit::Overridden(limit) => limit,
+            SoftErrorLimit::Unlimited => usize::MAX,
+        }
+    }
+}
+
+/// Override how many times each call site reporting `category` passes soft errors to the
+/// handler. Setting a category again replaces the previous limit.
+pub fn set_soft_error_limit(category: &'static str, limit: usize) {
+    SOFT_ERROR_LIMITS.lock().unwrap().insert(category, limit);
+}
+
+/// The limit applying to soft errors with `category`, e.g. for diagnostics explaining why some
+/// soft errors were not logged.
+pub fn effective_soft_error_limit(category: &str) -> SoftErrorLimit {
+    if in_verbose_scope() {
+        return SoftErrorLimit::Unlimited;
+    }
+    match SOFT_ERROR_LIMITS.lock().unwrap().get(category) {
+        Some(limit) => SoftErrorLimit::Overridden(*limit),
+        None => SoftErrorLimit::Default(DEFAULT_SOFT_ERROR_LIMIT),
+    }
+}
+
+/// Use `config`, in the format of `$BUCK2_HARD_ERROR`, instead of `$BUCK2_HARD_ERROR` for soft
+/// errors attributed to any of `targets`, unconfigured target labels like `root//foo:bar`. This
+/// lets a migration upgrade a soft error globally while exempting known-flaky targets. Setting
+/// a target again replaces its override.
+pub fn set_target_hard_error_override(targets: &[&str], config: &str) -> anyhow::Result<()> {
+    let config = Arc::new(HardErrorConfig::from_str(config)?);
+    let mut overrides = TARGET_HARD_ERROR_OVERRIDES.lock().unwrap();
+    for target in targets {
+        overrides.insert((*target).to_owned(), config.dupe());
+    }
+    Ok(())
+}
+
+pub fn clear_target_hard_error_overrides() {
+    TARGET_HARD_ERROR_OVERRIDES.lock().unwrap().clear();
+}
+
+/// Run `f` with the soft errors it reports on this thread attributed to `target`, so that an
+/// override set with `set_target_hard_error_override` applies to them. Used during analysis.
+pub fn with_soft_error_target<R>(target: &str, f: impl FnOnce() -> R) -> R {
+    struct Restore(Option<String>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            CURRENT_TARGET.with(|current| *current.borrow_mut() = self.0.take());
+        }
+    }
+
+    let _restore = Restore(CURRENT_TARGET.with(|current| current.replace(Some(target.to_owned()))));
+    f()
+}
+
+/// Like `with_soft_error_target`, but for every poll of `fut`, wherever it runs, so that the
+/// target also applies across `.await`s.
+pub fn with_soft_error_target_async<F: Future>(target: &str, fut: F) -> SoftErrorTargetFuture<F> {
+    SoftErrorTargetFuture {
+        target: target.to_owned(),
+        fut,
+    }
+}
+
+#[pin_project]
+pub struct SoftErrorTargetFuture<F> {
+    target: String,
+    #[pin]
+    fut: F,
+}
+
+impl<F: Future> Future for SoftErrorTargetFuture<F> {
+    type Output = F::Output;
+
+    fn poll(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Self::Output> {
+        let this = self.project();
+        with_soft_error_target(this.target, || this.fut.poll(cx))
+    }
+}
+
+thread_local! {
+    /// How many `SoftErrorVerboseScope`s are alive on this thread.
+    static VERBOSE_SCOPES: Cell<usize> = Cell::new(0);
+}
+
+fn in_verbose_scope() -> bool {
+    VERBOSE_SCOPES.with(|scopes| scopes.get() > 0)
+}
+
+/// While alive, every soft error reported on the thread which entered the scope is passed to
+/// the handler, whatever the limit of its category, e.g. to get full logs of one noisy phase.
+/// These errors still count towards `soft_error_count`, but not towards the limit of their call
+/// site, so once the scope ends the call site passes errors to the handler as if they had never
+/// fired. Scopes can be nested.
+#[must_use]
+pub struct SoftErrorVerboseScope {
+    // The scope only applies to the thread which entered it, so it must be dropped there.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl SoftErrorVerboseScope {
+    pub fn enter() -> SoftErrorVerboseScope {
+        VERBOSE_SCOPES.with(|scopes| scopes.set(scopes.get() + 1));
+        SoftErrorVerboseScope {
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl Drop for SoftErrorVerboseScope {
+    fn drop(&mut self) {
+        VERBOSE_SCOPES.with(|scopes| scopes.set(scopes.get() - 1));
+    }
+}
+
+/// Ownership information for a soft error category, passed to the handler so that reports
+/// can be routed to the team responsible for the category.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SoftErrorAnnotation {
+    pub owner: &'static str,
+    pub task_link: Option<&'static str>,
+}
+
+/// How serious a soft error is, for handlers to filter on. Severity doesn't affect whether a
+/// soft error is upgraded to a hard error by `$BUCK2_HARD_ERROR`.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Dupe,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    derive_more::Display
+)]
+pub enum Severity {
+    #[display(fmt = "info")]
+    Info,
+    #[display(fmt = "warning")]
+    Warning,
+    #[display(fmt = "error")]
+    Error,
+}
+
+impl Default for Severity {
+    fn default() -> Severity {
+        Severity::Warning
+    }
+}
+
+/// Attach an annotation to a soft error category. Registering a category again replaces the
+/// previous annotation.
+pub fn annotate_soft_error_category(category: &'static str, annotation: SoftErrorAnnotation) {
+    SOFT_ERROR_ANNOTATIONS
+        .lock()
+        .unwrap()
+        .insert(category, annotation);
+}
 
 /// Throw a "soft_error" i.e. one that is destined to become a hard error
 /// in the near future. The macro lives in this crate to allow it be
@@ -41,14 +280,23 @@ static ALL_SOFT_ERROR_COUNTERS: Mutex<Vec<&'static AtomicUsize>> = Mutex::new(Ve
 /// Soft errors from Meta internal runs can be viewed
 /// [in logview](https://www.internalfb.com/logview/overview/buck2).
 ///
+/// Soft errors are reported with `Severity::Warning`, pass `severity: ...` as a third argument
+/// to report them with another severity.
+///
+/// With `$BUCK2_SOFT_ERROR_BACKTRACE=true`, the handler also gets a backtrace of where the soft
+/// error fired.
+///
 /// You'll get the error back as the Ok() value if it wasn't thrown, otherwise you get a Err() to
 /// propagate.
 #[macro_export]
 macro_rules! soft_error(
-    ($category:expr, $err:expr) => { {
+    ($category:expr, $err:expr) => {
+        $crate::soft_error!($category, $err, severity: $crate::error::Severity::Warning)
+    };
+    ($category:expr, $err:expr, severity: $severity:expr) => { {
         static COUNT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
         static ONCE: std::sync::Once = std::sync::Once::new();
-        $crate::error::handle_soft_error($category, $err, &COUNT, &ONCE, (file!(), line!(), column!()), false)
+        $crate::error::handle_soft_error($category, $err, &COUNT, &ONCE, (file!(), line!(), column!()), false, $severity)
     } }
 );
 
@@ -59,7 +307,7 @@ macro_rules! quiet_soft_error(
     ($category:expr, $err:expr) => { {
         static COUNT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
         static ONCE: std::sync::Once = std::sync::Once::new();
-        $crate::error::handle_soft_error($category, $err, &COUNT, &ONCE, (file!(), line!(), column!()), true)
+        $crate::error::handle_soft_error($category, $err, &COUNT, &ONCE, (file!(), line!(), column!()), true, $crate::error::Severity::Warning)
     } }
 );
 
@@ -72,44 +320,227 @@ pub fn handle_soft_error(
     once: &std::sync::Once,
     loc: (&'static str, u32, u32),
     quiet: bool,
+    severity: Severity,
 ) -> anyhow::Result<anyhow::Error> {
     once.call_once(|| {
-        ALL_SOFT_ERROR_COUNTERS.lock().unwrap().push(count);
+        ALL_SOFT_ERROR_COUNTERS
+            .lock()
+            .unwrap()
+            .push((category, count));
     });
 
-    // We want to limit each error to appearing at most 10 times in a build (no point spamming people)
-    if count.fetch_add(1, Ordering::SeqCst) < 10 {
+    // We want to limit each error to appearing at most a few times in a build (no point spamming people)
+    // Errors in a verbose scope are still counted, they just don't use up the limit.
+    let previous = count.fetch_add(1, Ordering::SeqCst);
+    let counter_key = count as *const AtomicUsize as usize;
+    let within_limit = if in_verbose_scope() {
+        *VERBOSE_SOFT_ERROR_COUNTS
+            .lock()
+            .unwrap()
+            .entry(counter_key)
+            .or_default() += 1;
+        true
+    } else {
+        let limit = effective_soft_error_limit(category).limit();
+        // Only look up the errors fired in verbose scopes once they could matter.
+        previous < limit || {
+            let verbose = VERBOSE_SOFT_ERROR_COUNTS
+                .lock()
+                .unwrap()
+                .get(&counter_key)
+                .copied()
+                .unwrap_or_default();
+            previous.saturating_sub(verbose) < limit
+        }
+    };
+    if within_limit {
         if let Some(handler) = HANDLER.get() {
-            handler(category, &err, loc, quiet);
+            let annotation = SOFT_ERROR_ANNOTATIONS
+                .lock()
+                .unwrap()
+                .get(category)
+                .copied();
+            let backtrace = if CAPTURE_BACKTRACE.load(Ordering::Relaxed) {
+                Some(Backtrace::force_capture())
+            } else {
+                None
+            };
+            handler(
+                category,
+                &err,
+                loc,
+                quiet,
+                annotation.as_ref(),
+                severity,
+                backtrace.as_ref(),
+            );
         }
     }
 
-    if let Some(h) = HARD_ERROR.get()? {
-        if h.should_hard_error(category) {
-            return Err(err.context("Upgraded warning to failure via $BUCK2_HARD_ERROR"));
-        }
+    if should_hard_error(category, hard_error_config_or_default(HARD_ERROR.get())) {
+        return Err(err.context("Upgraded warning to failure via $BUCK2_HARD_ERROR"));
     }
 
     Ok(err)
 }
 
+/// Whether a soft error with `category` is upgraded to a hard error, according to the override
+/// for the target it is attributed to if there is one, and to `global` otherwise.
+fn should_hard_error(category: &str, global: Option<&HardErrorConfig>) -> bool {
+    let target_override = CURRENT_TARGET.with(|current| {
+        let current = current.borrow();
+        TARGET_HARD_ERROR_OVERRIDES
+            .lock()
+            .unwrap()
+            .get(current.as_deref()?)
+            .cloned()
+    });
+    match target_override {
+        Some(config) => config.should_hard_error(category),
+        None => global.map_or(false, |config| config.should_hard_error(category)),
+    }
+}
+
 #[allow(clippy::significant_drop_in_scrutinee)] // False positive.
 pub fn reset_soft_error_counters() {
-    for counter in ALL_SOFT_ERROR_COUNTERS.lock().unwrap().iter() {
+    for (_, counter) in ALL_SOFT_ERROR_COUNTERS.lock().unwrap().iter() {
         counter.store(0, Ordering::Relaxed);
     }
+    VERBOSE_SOFT_ERROR_COUNTS.lock().unwrap().clear();
 }
 
-pub fn initialize(handler: SoftErrorHandler) -> anyhow::Result<()> {
-    HARD_ERROR.get()?;
+/// Like `reset_soft_error_counters`, but only for call sites reporting `category`.
+/// Does nothing if no soft error with this category has fired.
+#[allow(clippy::significant_drop_in_scrutinee)] // False positive.
+pub fn reset_soft_error_counter(category: &str) {
+    for (_, counter) in ALL_SOFT_ERROR_COUNTERS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(c, _)| *c == category)
+    {
+        counter.store(0, Ordering::Relaxed);
+        VERBOSE_SOFT_ERROR_COUNTS
+            .lock()
+            .unwrap()
+            .remove(&(*counter as *const AtomicUsize as usize));
+    }
+}
+
+/// How many times soft errors with `category` have fired since the counters were last reset,
+/// including the ones not passed to the handler because a call site fired too often.
+/// Zero if no soft error with this category has fired.
+pub fn soft_error_count(category: &str) -> usize {
+    ALL_SOFT_ERROR_COUNTERS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(c, _)| *c == category)
+        .map(|(_, counter)| counter.load(Ordering::Relaxed))
+        .sum()
+}
 
+pub fn initialize(handler: SoftErrorHandler) -> anyhow::Result<()> {
     if let Err(_e) = HANDLER.set(handler) {
         panic!("Cannot initialize soft_error handler more than once");
     }
 
+    // Report a malformed config now that there is a handler to report it to.
+    hard_error_config_or_default(HARD_ERROR.get());
+
+    match BACKTRACE.get_copied() {
+        Ok(capture) => CAPTURE_BACKTRACE.store(capture.unwrap_or(false), Ordering::Relaxed),
+        Err(e) => {
+            let _ignore = soft_error!("invalid_soft_error_backtrace_config", e);
+        }
+    }
+
     Ok(())
 }
 
+/// A malformed `$BUCK2_HARD_ERROR` is reported once as a soft error and then treated like an
+/// unset one, i.e. no soft error is upgraded, rather than making every soft error fail.
+fn hard_error_config_or_default(
+    config: anyhow::Result<Option<&HardErrorConfig>>,
+) -> Option<&HardErrorConfig> {
+    static REPORTED: AtomicBool = AtomicBool::new(false);
+    match config {
+        Ok(config) => config,
+        Err(e) => {
+            // Reporting goes through `handle_soft_error`, which calls back into this function,
+            // so mark the error as reported first.
+            if !REPORTED.swap(true, Ordering::SeqCst) {
+                let _ignore = soft_error!("invalid_hard_error_config", e);
+            }
+            None
+        }
+    }
+}
+
+/// How many messages `SoftErrorReport` keeps for each category.
+const SOFT_ERROR_REPORT_SAMPLES: usize = 3;
+
+/// A soft error handler which keeps errors in a buffer rather than reporting them as they
+/// happen, for tools that want a summary at the end of a build instead of interleaved output.
+/// Install it with `initialize(buffer.handler())` and collect the summary with `drain`.
+#[derive(Clone, Dupe, Default)]
+pub struct BufferingSoftErrorHandler {
+    errors: Arc<Mutex<Vec<(&'static str, String)>>>,
+}
+
+/// Soft errors collected by `BufferingSoftErrorHandler`, grouped by category.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SoftErrorReport {
+    pub categories: BTreeMap<&'static str, SoftErrorCategoryReport>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SoftErrorCategoryReport {
+    /// Number of errors reported in this category. Like every handler, the buffer only sees the
+    /// first few errors of each call site.
+    pub count: usize,
+    /// The messages of the first few errors.
+    pub samples: Vec<String>,
+}
+
+impl BufferingSoftErrorHandler {
+    pub fn new() -> BufferingSoftErrorHandler {
+        BufferingSoftErrorHandler::default()
+    }
+
+    /// A handler recording into this buffer, to be passed to `initialize`.
+    pub fn handler(&self) -> SoftErrorHandler {
+        let errors = self.errors.dupe();
+        Box::new(
+            move |category: &'static str,
+                  err: &anyhow::Error,
+                  _loc: (&'static str, u32, u32),
+                  _quiet: bool,
+                  _annotation: Option<&SoftErrorAnnotation>,
+                  _severity: Severity,
+                  _backtrace: Option<&Backtrace>| {
+                errors
+                    .lock()
+                    .unwrap()
+                    .push((category, format!("{:#}", err)));
+            },
+        )
+    }
+
+    /// Summarize the errors recorded so far and empty the buffer.
+    pub fn drain(&self) -> SoftErrorReport {
+        let mut report = SoftErrorReport::default();
+        for (category, message) in self.errors.lock().unwrap().drain(..) {
+            let category = report.categories.entry(category).or_default();
+            category.count += 1;
+            if category.samples.len() < SOFT_ERROR_REPORT_SAMPLES {
+                category.samples.push(message);
+            }
+        }
+        report
+    }
+}
+
 /// Parse either a boolean or `only=category1,category2`
 enum HardErrorConfig {
     Bool(bool),
@@ -123,6 +554,83 @@ impl HardErrorConfig {
             Self::Selected(s) => s.contains(category),
         }
     }
+
+    fn categories(&self) -> HardErrorCategories {
+        match self {
+            Self::Bool(true) => HardErrorCategories::All,
+            Self::Bool(false) => HardErrorCategories::Only(BTreeSet::new()),
+            Self::Selected(s) => HardErrorCategories::Only(s.iter().cloned().collect()),
+        }
+    }
+
+    fn diff(&self, new: &HardErrorConfig) -> HardErrorConfigDiff {
+        let old = self.categories();
+        let new = new.categories();
+        HardErrorConfigDiff {
+            upgraded: new.intersection(&old.complement()),
+            relaxed: old.intersection(&new.complement()),
+            unchanged: old.intersection(&new),
+        }
+    }
+}
+
+/// Compare two values of `$BUCK2_HARD_ERROR`, e.g. to check that a config change only upgrades
+/// the categories it is expected to.
+pub fn diff_hard_error_configs(old: &str, new: &str) -> anyhow::Result<HardErrorConfigDiff> {
+    Ok(HardErrorConfig::from_str(old)?.diff(&HardErrorConfig::from_str(new)?))
+}
+
+/// How the soft error categories upgraded to hard errors differ between two configs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HardErrorConfigDiff {
+    /// Categories upgraded by the new config but not the old one.
+    pub upgraded: HardErrorCategories,
+    /// Categories upgraded by the old config but not the new one.
+    pub relaxed: HardErrorCategories,
+    /// Categories upgraded by both configs.
+    pub unchanged: HardErrorCategories,
+}
+
+/// A set of soft error categories.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HardErrorCategories {
+    All,
+    /// Never empty, that's `All`.
+    AllExcept(BTreeSet<String>),
+    Only(BTreeSet<String>),
+}
+
+impl HardErrorCategories {
+    /// Every category but `excluded`, normalized so that excluding nothing is `All`, and
+    /// comparing with `==` works.
+    fn all_except(excluded: BTreeSet<String>) -> Self {
+        if excluded.is_empty() {
+            Self::All
+        } else {
+            Self::AllExcept(excluded)
+        }
+    }
+
+    fn complement(&self) -> Self {
+        match self {
+            Self::All => Self::Only(BTreeSet::new()),
+            Self::AllExcept(s) => Self::Only(s.clone()),
+            Self::Only(s) => Self::all_except(s.clone()),
+        }
+    }
+
+    fn intersection(&self, other: &Self) -> Self {
+        match (self, other) {
+            (Self::All, x) | (x, Self::All) => x.clone(),
+            (Self::Only(a), Self::Only(b)) => Self::Only(a.intersection(b).cloned().collect()),
+            (Self::Only(a), Self::AllExcept(b)) | (Self::AllExcept(b), Self::Only(a)) => {
+                Self::Only(a.difference(b).cloned().collect())
+            }
+            (Self::AllExcept(a), Self::AllExcept(b)) => {
+                Self::all_except(a.union(b).cloned().collect())
+            }
+        }
+    }
 }
 
 impl FromStr for HardErrorConfig {
@@ -160,22 +668,35 @@ mod tests {
 
     use super::*;
     use crate::error::initialize;
+    use crate::error::reset_soft_error_counter;
     use crate::error::reset_soft_error_counters;
     use crate::error::HardErrorConfig;
     use crate::soft_error;
 
     static RESULT: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    static ANNOTATIONS: Mutex<Vec<Option<SoftErrorAnnotation>>> = Mutex::new(Vec::new());
+    static SEVERITIES: Mutex<Vec<Severity>> = Mutex::new(Vec::new());
+    static BACKTRACES: Mutex<Vec<Option<String>>> = Mutex::new(Vec::new());
 
     fn mock_handler(
         category: &'static str,
         err: &anyhow::Error,
         loc: (&'static str, u32, u32),
         quiet: bool,
+        annotation: Option<&SoftErrorAnnotation>,
+        severity: Severity,
+        backtrace: Option<&Backtrace>,
     ) {
         RESULT
             .lock()
             .unwrap()
             .push(format!("{:?}, : {} : {} : {}", loc, err, category, quiet));
+        ANNOTATIONS.lock().unwrap().push(annotation.copied());
+        SEVERITIES.lock().unwrap().push(severity);
+        BACKTRACES
+            .lock()
+            .unwrap()
+            .push(backtrace.map(|b| b.to_string()));
     }
 
     fn test_init() -> MutexGuard<'static, ()> {
@@ -190,6 +711,9 @@ mod tests {
         });
 
         RESULT.lock().unwrap().clear();
+        ANNOTATIONS.lock().unwrap().clear();
+        SEVERITIES.lock().unwrap().clear();
+        BACKTRACES.lock().unwrap().clear();
 
         guard
     }
@@ -242,6 +766,260 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_reset_single_counter() {
+        let _guard = test_init();
+
+        fn fire_both() {
+            for _ in 0..20 {
+                let _ignore = soft_error!("test_reset_single_counter_a", anyhow::anyhow!("A"));
+                let _ignore = soft_error!("test_reset_single_counter_b", anyhow::anyhow!("B"));
+            }
+        }
+        let count = |category: &str| {
+            RESULT
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|r| r.contains(category))
+                .count()
+        };
+
+        fire_both();
+        assert_eq!(10, count("test_reset_single_counter_a"));
+        assert_eq!(10, count("test_reset_single_counter_b"));
+
+        reset_soft_error_counter("test_reset_single_counter_a");
+        reset_soft_error_counter("test_no_such_category");
+        fire_both();
+        assert_eq!(20, count("test_reset_single_counter_a"));
+        assert_eq!(10, count("test_reset_single_counter_b"));
+    }
+
+    #[test]
+    fn test_soft_error_count() {
+        let _guard = test_init();
+
+        for _ in 0..13 {
+            let _ignore = soft_error!("test_soft_error_count", anyhow::anyhow!("Counted"));
+        }
+        let _ignore = soft_error!("test_soft_error_count", anyhow::anyhow!("Other call site"));
+
+        assert_eq!(14, soft_error_count("test_soft_error_count"));
+        assert_eq!(0, soft_error_count("test_no_such_category"));
+
+        reset_soft_error_counter("test_soft_error_count");
+        assert_eq!(0, soft_error_count("test_soft_error_count"));
+    }
+
+    #[test]
+    fn test_soft_error_limit() {
+        let _guard = test_init();
+
+        set_soft_error_limit("test_soft_error_limit_overridden", 3);
+        assert_eq!(
+            SoftErrorLimit::Overridden(3),
+            effective_soft_error_limit("test_soft_error_limit_overridden")
+        );
+        assert_eq!(
+            SoftErrorLimit::Default(10),
+            effective_soft_error_limit("test_soft_error_limit_default")
+        );
+
+        for _ in 0..5 {
+            let _ignore = soft_error!(
+                "test_soft_error_limit_overridden",
+                anyhow::anyhow!("Limited")
+            );
+        }
+        assert_eq!(3, RESULT.lock().unwrap().len());
+    }
+
+    #[test]
+    fn test_verbose_scope() {
+        let _guard = test_init();
+
+        fn fire(n: usize) {
+            for _ in 0..n {
+                let _ignore = soft_error!("test_verbose_scope", anyhow::anyhow!("Noisy"));
+            }
+        }
+
+        {
+            let _verbose = SoftErrorVerboseScope::enter();
+            assert_eq!(
+                SoftErrorLimit::Unlimited,
+                effective_soft_error_limit("test_verbose_scope")
+            );
+            fire(50);
+        }
+        assert_eq!(50, RESULT.lock().unwrap().len());
+        assert_eq!(50, soft_error_count("test_verbose_scope"));
+
+        // Errors fired within the scope don't use up the limit of the call site.
+        assert_eq!(
+            SoftErrorLimit::Default(10),
+            effective_soft_error_limit("test_verbose_scope")
+        );
+        fire(50);
+        assert_eq!(60, RESULT.lock().unwrap().len());
+        assert_eq!(100, soft_error_count("test_verbose_scope"));
+    }
+
+    #[test]
+    fn test_buffering_handler() {
+        let buffer = BufferingSoftErrorHandler::new();
+        let handler = buffer.handler();
+        let loc = (file!(), line!(), column!());
+        for i in 0..5 {
+            handler(
+                "test_buffered_a",
+                &anyhow::anyhow!("A{}", i),
+                loc,
+                false,
+                None,
+                Severity::Warning,
+                None,
+            );
+        }
+        handler(
+            "test_buffered_b",
+            &anyhow::anyhow!("B"),
+            loc,
+            true,
+            None,
+            Severity::Warning,
+            None,
+        );
+
+        let report = buffer.drain();
+        assert_eq!(
+            vec!["test_buffered_a", "test_buffered_b"],
+            report.categories.keys().copied().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            SoftErrorCategoryReport {
+                count: 5,
+                samples: vec!["A0".to_owned(), "A1".to_owned(), "A2".to_owned()],
+            },
+            report.categories["test_buffered_a"]
+        );
+        assert_eq!(1, report.categories["test_buffered_b"].count);
+
+        assert_eq!(SoftErrorReport::default(), buffer.drain());
+    }
+
+    #[test]
+    fn test_annotated_category() {
+        let _guard = test_init();
+
+        let annotation = SoftErrorAnnotation {
+            owner: "build_infra",
+            task_link: Some("T12345"),
+        };
+        annotate_soft_error_category("test_annotated_category", annotation);
+
+        let _ignore = soft_error!("test_annotated_category", anyhow::anyhow!("Annotated"));
+        let _ignore = soft_error!("test_unannotated_category", anyhow::anyhow!("Plain"));
+
+        assert_eq!(vec![Some(annotation), None], *ANNOTATIONS.lock().unwrap());
+    }
+
+    #[test]
+    fn test_severity() {
+        let _guard = test_init();
+
+        let _ignore = soft_error!("test_severity_default", anyhow::anyhow!("Default"));
+        let _ignore = soft_error!(
+            "test_severity_info",
+            anyhow::anyhow!("Info"),
+            severity: Severity::Info
+        );
+        let _ignore = soft_error!(
+            "test_severity_error",
+            anyhow::anyhow!("Error"),
+            severity: Severity::Error
+        );
+        assert_eq!(
+            vec![Severity::Warning, Severity::Info, Severity::Error],
+            *SEVERITIES.lock().unwrap()
+        );
+
+        // A handler can drop errors below some severity.
+        let buffer = BufferingSoftErrorHandler::new();
+        let buffered = buffer.handler();
+        let filtering: SoftErrorHandler = Box::new(
+            move |category: &'static str,
+                  err: &anyhow::Error,
+                  loc: (&'static str, u32, u32),
+                  quiet: bool,
+                  annotation: Option<&SoftErrorAnnotation>,
+                  severity: Severity,
+                  backtrace: Option<&Backtrace>| {
+                if severity > Severity::Info {
+                    buffered(category, err, loc, quiet, annotation, severity, backtrace);
+                }
+            },
+        );
+        let loc = (file!(), line!(), column!());
+        for severity in [Severity::Info, Severity::Warning, Severity::Error] {
+            filtering(
+                "test_severity_filtered",
+                &anyhow::anyhow!("{}", severity),
+                loc,
+                false,
+                None,
+                severity,
+                None,
+            );
+        }
+        assert_eq!(
+            vec!["warning".to_owned(), "error".to_owned()],
+            buffer.drain().categories["test_severity_filtered"].samples
+        );
+    }
+
+    #[test]
+    fn test_backtrace() {
+        let _guard = test_init();
+
+        let _ignore = soft_error!("test_backtrace_disabled", anyhow::anyhow!("Disabled"));
+        CAPTURE_BACKTRACE.store(true, Ordering::Relaxed);
+        let _ignore = soft_error!("test_backtrace_enabled", anyhow::anyhow!("Enabled"));
+        CAPTURE_BACKTRACE.store(false, Ordering::Relaxed);
+
+        let backtraces = BACKTRACES.lock().unwrap();
+        assert_eq!(2, backtraces.len());
+        assert_eq!(None, backtraces[0]);
+        let backtrace = backtraces[1].as_ref().expect("a backtrace");
+        assert!(!backtrace.is_empty());
+    }
+
+    #[test]
+    fn test_malformed_hard_error_config() {
+        let _guard = test_init();
+
+        let malformed = HardErrorConfig::from_str("garbage");
+        let malformed = || {
+            malformed
+                .as_ref()
+                .map(Some)
+                .map_err(|e| anyhow::anyhow!("{}", e))
+        };
+        assert!(hard_error_config_or_default(malformed()).is_none());
+        assert!(hard_error_config_or_default(malformed()).is_none());
+
+        let reported: Vec<String> = RESULT
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|r| r.contains("invalid_hard_error_config"))
+            .cloned()
+            .collect();
+        assert_eq!(1, reported.len(), "{:?}", reported);
+        assert!(reported[0].contains("`garbage`"), "{:?}", reported);
+    }
+
     #[test]
     fn test_hard_error() -> anyhow::Result<()> {
         assert!(HardErrorConfig::from_str("true")?.should_hard_error("foo"));
@@ -252,4 +1030,84 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_target_hard_error_override() -> anyhow::Result<()> {
+        let _guard = test_init();
+
+        let upgrade_all = HardErrorConfig::from_str("true")?;
+        let category = "test_target_hard_error_override";
+        set_target_hard_error_override(&["root//flaky:target"], "false")?;
+
+        assert!(should_hard_error(category, Some(&upgrade_all)));
+        assert!(with_soft_error_target("root//flaky:target", || {
+            !should_hard_error(category, Some(&upgrade_all))
+        }));
+        assert!(with_soft_error_target("root//other:target", || {
+            should_hard_error(category, Some(&upgrade_all))
+        }));
+        assert!(should_hard_error(category, Some(&upgrade_all)));
+
+        set_target_hard_error_override(
+            &["root//strict:target"],
+            "only=test_target_hard_error_override",
+        )?;
+        assert!(with_soft_error_target("root//strict:target", || {
+            soft_error!(
+                "test_target_hard_error_override",
+                anyhow::anyhow!("Upgraded")
+            )
+            .is_err()
+        }));
+
+        assert!(futures::executor::block_on(with_soft_error_target_async(
+            "root//strict:target",
+            async {
+                futures::future::ready(()).await;
+                should_hard_error(category, None)
+            }
+        )));
+
+        clear_target_hard_error_overrides();
+        assert!(!with_soft_error_target("root//strict:target", || {
+            should_hard_error(category, None)
+        }));
+        Ok(())
+    }
+
+    #[test]
+    fn test_hard_error_config_diff() -> anyhow::Result<()> {
+        fn only(categories: &[&str]) -> HardErrorCategories {
+            HardErrorCategories::Only(categories.iter().map(|c| (*c).to_owned()).collect())
+        }
+
+        assert_eq!(
+            HardErrorConfigDiff {
+                upgraded: only(&["c"]),
+                relaxed: only(&["b"]),
+                unchanged: only(&["a"]),
+            },
+            diff_hard_error_configs("only=a,b", "only=a,c")?
+        );
+
+        assert_eq!(
+            HardErrorConfigDiff {
+                upgraded: HardErrorCategories::All,
+                relaxed: only(&[]),
+                unchanged: only(&[]),
+            },
+            diff_hard_error_configs("false", "true")?
+        );
+
+        assert_eq!(
+            HardErrorConfigDiff {
+                upgraded: only(&[]),
+                relaxed: HardErrorCategories::AllExcept(["a".to_owned()].into_iter().collect()),
+                unchanged: only(&["a"]),
+            },
+            diff_hard_error_configs("true", "only=a")?
+        );
+
+        Ok(())
+    }
 }
@@ -7,12 +7,21 @@
  * of this source tree.
  */
 
+use std::collections::HashMap;
 use std::fmt;
 use std::fmt::Display;
 use std::marker::PhantomData;
+use std::ops::Deref;
 use std::sync::Arc;
+use std::sync::Mutex;
 
 use allocative::Allocative;
+use dupe::Dupe;
+use fnv::FnvHasher;
+use internment_tweaks::Equiv;
+use internment_tweaks::Intern;
+use internment_tweaks::StaticInterner;
+use once_cell::sync::Lazy;
 
 use crate::cells::cell_path::CellPath;
 
@@ -42,8 +51,58 @@ impl Display for ProviderId {
     }
 }
 
+impl Equiv<ProviderId> for ProviderId {
+    fn equivalent(&self, key: &ProviderId) -> bool {
+        self == key
+    }
+}
+
+static INTERNER: StaticInterner<ProviderId, FnvHasher> = StaticInterner::new();
+
+/// A `ProviderId` stored in a global table, so that equality is a pointer comparison.
+/// Provider collections are keyed by these, which keeps checking the presence of a provider
+/// across many collections cheap.
+#[derive(
+    Debug, Clone, Copy, Dupe, Hash, Eq, PartialEq, Ord, PartialOrd, Allocative
+)]
+pub struct InternedProviderId(Intern<ProviderId>);
+
+impl InternedProviderId {
+    pub fn new(id: &ProviderId) -> InternedProviderId {
+        match InternedProviderId::get(id) {
+            Some(interned) => interned,
+            None => InternedProviderId(INTERNER.intern(id.clone())),
+        }
+    }
+
+    /// The interned version of `id`, without interning it if it is not there yet. No collection
+    /// can contain a provider whose id has never been interned.
+    pub fn get(id: &ProviderId) -> Option<InternedProviderId> {
+        INTERNER.get(id).map(InternedProviderId)
+    }
+
+    pub fn id(&self) -> &'static ProviderId {
+        self.0.deref_static()
+    }
+}
+
+impl Deref for InternedProviderId {
+    type Target = ProviderId;
+
+    fn deref(&self) -> &ProviderId {
+        self.id()
+    }
+}
+
+impl Display for InternedProviderId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Display::fmt(self.id(), f)
+    }
+}
+
 pub struct ProviderIdWithType<T> {
     id: Arc<ProviderId>,
+    interned: InternedProviderId,
     t: PhantomData<T>,
 }
 
@@ -52,18 +111,38 @@ impl<T> ProviderIdWithType<T> {
         &self.id
     }
 
+    pub fn interned(&self) -> InternedProviderId {
+        self.interned
+    }
+
     pub fn new(path: Option<CellPath>, name: String) -> Self {
+        let id = ProviderId { path, name };
         Self {
-            id: Arc::new(ProviderId { path, name }),
+            interned: InternedProviderId::new(&id),
+            id: Arc::new(id),
             t: Default::default(),
         }
     }
 }
 
+static ORDINALS: Lazy<Mutex<HashMap<InternedProviderId, u32>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
 impl ProviderId {
     pub fn name(&self) -> &str {
         &self.name
     }
+
+    /// A small number identifying this provider, e.g. to refer to providers in serialized output
+    /// through a side table rather than by their full id. Ordinals are assigned in the order
+    /// providers are first asked for, so they are only stable within one daemon process.
+    pub fn ordinal(&self) -> u32 {
+        let mut ordinals = ORDINALS.lock().unwrap();
+        let next = u32::try_from(ordinals.len()).expect("fewer than 2^32 distinct providers");
+        *ordinals
+            .entry(InternedProviderId::new(self))
+            .or_insert(next)
+    }
 }
 
 pub mod testing {
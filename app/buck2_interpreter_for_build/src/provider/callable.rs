@@ -9,6 +9,7 @@
 
 use std::sync::Arc;
 
+use buck2_core::provider::id::InternedProviderId;
 use buck2_core::provider::id::ProviderId;
 use dupe::Dupe;
 use starlark::any::ProvidesStaticType;
@@ -27,6 +28,10 @@ enum ProviderCallableError {
 pub trait ProviderCallableLike {
     fn id(&self) -> Option<&Arc<ProviderId>>;
 
+    /// The interned version of `id`, stored alongside it when it is set so that looking the
+    /// provider up in a collection doesn't go through the global interner.
+    fn interned_id(&self) -> Option<InternedProviderId>;
+
     /// Frozen callables should always have this set. It's an error if somehow it doesn't.
     fn require_id(&self) -> anyhow::Result<Arc<ProviderId>> {
         match self.id() {
@@ -35,6 +40,14 @@ pub trait ProviderCallableLike {
         }
     }
 
+    /// Like `require_id`, for `interned_id`.
+    fn require_interned_id(&self) -> anyhow::Result<InternedProviderId> {
+        match self.interned_id() {
+            Some(id) => Ok(id),
+            None => Err(ProviderCallableError::ProviderCallableMissingID.into()),
+        }
+    }
+
     fn provider_callable_documentation(
         &self,
         docs: &Option<DocString>,